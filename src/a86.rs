@@ -1,30 +1,43 @@
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     fs, iter,
+    ops::Range,
     path::Path,
 };
 
 use anyhow::{Context, Result, bail, ensure};
 use bimap::BiMap;
-use elf::{ElfBytes, endian::AnyEndian};
-use iced_x86::{Code, Decoder, DecoderOptions, OpKind};
+use elf::{
+    ElfBytes,
+    abi::{
+        ET_REL, R_X86_64_32, R_X86_64_32S, R_X86_64_64, R_X86_64_PC32, R_X86_64_PLT32, SHF_ALLOC,
+        SHN_UNDEF, SHT_RELA, STT_FUNC,
+    },
+    endian::AnyEndian,
+    section::SectionHeader,
+};
+use iced_x86::{
+    Code, Decoder, DecoderOptions, FlowControl, Formatter, MemoryOperand, Mnemonic, NasmFormatter,
+    OpKind,
+};
+
+use crate::{macho, nasm};
 
 pub type Address = u64;
 
-#[derive(Debug, Clone, Copy)]
+/// A general-purpose register, of any width. Registers narrower than 64 bits
+/// are part of a full one; see [`Register::full`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Register {
-    // 32-bit registers
-    Eax,
-    R9d,
     // 64-bit registers
     Rax,
     Rbx,
     Rcx,
     Rdx,
-    Rbp,
-    Rsp,
     Rsi,
     Rdi,
+    Rbp,
+    Rsp,
     R8,
     R9,
     R10,
@@ -33,208 +46,1039 @@ pub enum Register {
     R13,
     R14,
     R15,
+    // 32-bit registers
+    Eax,
+    Ebx,
+    Ecx,
+    Edx,
+    Esi,
+    Edi,
+    Ebp,
+    Esp,
+    R8d,
+    R9d,
+    R10d,
+    R11d,
+    R12d,
+    R13d,
+    R14d,
+    R15d,
+    // 16-bit registers
+    Ax,
+    Bx,
+    Cx,
+    Dx,
+    Si,
+    Di,
+    Bp,
+    Sp,
+    R8w,
+    R9w,
+    R10w,
+    R11w,
+    R12w,
+    R13w,
+    R14w,
+    R15w,
+    // 8-bit registers
+    Al,
+    Bl,
+    Cl,
+    Dl,
+    Sil,
+    Dil,
+    Bpl,
+    Spl,
+    R8b,
+    R9b,
+    R10b,
+    R11b,
+    R12b,
+    R13b,
+    R14b,
+    R15b,
+    // The second byte of the first four
+    Ah,
+    Bh,
+    Ch,
+    Dh,
+}
+
+/// Each register and its iced counterpart
+const REGISTERS: [(Register, iced_x86::Register); 68] = {
+    use Register as r;
+    use iced_x86::Register as ir;
+    [
+        (r::Rax, ir::RAX),
+        (r::Rbx, ir::RBX),
+        (r::Rcx, ir::RCX),
+        (r::Rdx, ir::RDX),
+        (r::Rsi, ir::RSI),
+        (r::Rdi, ir::RDI),
+        (r::Rbp, ir::RBP),
+        (r::Rsp, ir::RSP),
+        (r::R8, ir::R8),
+        (r::R9, ir::R9),
+        (r::R10, ir::R10),
+        (r::R11, ir::R11),
+        (r::R12, ir::R12),
+        (r::R13, ir::R13),
+        (r::R14, ir::R14),
+        (r::R15, ir::R15),
+        (r::Eax, ir::EAX),
+        (r::Ebx, ir::EBX),
+        (r::Ecx, ir::ECX),
+        (r::Edx, ir::EDX),
+        (r::Esi, ir::ESI),
+        (r::Edi, ir::EDI),
+        (r::Ebp, ir::EBP),
+        (r::Esp, ir::ESP),
+        (r::R8d, ir::R8D),
+        (r::R9d, ir::R9D),
+        (r::R10d, ir::R10D),
+        (r::R11d, ir::R11D),
+        (r::R12d, ir::R12D),
+        (r::R13d, ir::R13D),
+        (r::R14d, ir::R14D),
+        (r::R15d, ir::R15D),
+        (r::Ax, ir::AX),
+        (r::Bx, ir::BX),
+        (r::Cx, ir::CX),
+        (r::Dx, ir::DX),
+        (r::Si, ir::SI),
+        (r::Di, ir::DI),
+        (r::Bp, ir::BP),
+        (r::Sp, ir::SP),
+        (r::R8w, ir::R8W),
+        (r::R9w, ir::R9W),
+        (r::R10w, ir::R10W),
+        (r::R11w, ir::R11W),
+        (r::R12w, ir::R12W),
+        (r::R13w, ir::R13W),
+        (r::R14w, ir::R14W),
+        (r::R15w, ir::R15W),
+        (r::Al, ir::AL),
+        (r::Bl, ir::BL),
+        (r::Cl, ir::CL),
+        (r::Dl, ir::DL),
+        (r::Sil, ir::SIL),
+        (r::Dil, ir::DIL),
+        (r::Bpl, ir::BPL),
+        (r::Spl, ir::SPL),
+        (r::R8b, ir::R8L),
+        (r::R9b, ir::R9L),
+        (r::R10b, ir::R10L),
+        (r::R11b, ir::R11L),
+        (r::R12b, ir::R12L),
+        (r::R13b, ir::R13L),
+        (r::R14b, ir::R14L),
+        (r::R15b, ir::R15L),
+        (r::Ah, ir::AH),
+        (r::Bh, ir::BH),
+        (r::Ch, ir::CH),
+        (r::Dh, ir::DH),
+    ]
+};
+
+impl Register {
+    /// The 64-bit register this one is part of, e.g. `rax` for `al`
+    pub fn full(self) -> Register {
+        iced_x86::Register::from(self)
+            .full_register()
+            .try_into()
+            .unwrap()
+    }
+
+    /// The register's width in bytes
+    pub fn size(self) -> usize {
+        iced_x86::Register::from(self).size()
+    }
+
+    /// Whether writing the register clears the rest of its full register, as
+    /// writing a 32-bit register does. Narrower writes leave the rest as is.
+    pub fn zero_extends(self) -> bool {
+        self.size() == 4
+    }
+
+    /// Whether the registers overlap, so writing one changes the other
+    pub fn aliases(self, other: Register) -> bool {
+        use Register::*;
+        let high = |r| matches!(r, Ah | Bh | Ch | Dh);
+        // `ah` and `al` are both part of `rax`, but don't overlap each other
+        self.full() == other.full()
+            && !(self.size() == 1 && other.size() == 1 && high(self) != high(other))
+    }
 }
 
 impl TryFrom<iced_x86::Register> for Register {
     type Error = anyhow::Error;
 
     fn try_from(value: iced_x86::Register) -> std::result::Result<Self, Self::Error> {
-        use Register as r;
-        use iced_x86::Register as doodoo;
-        Ok(match value {
-            doodoo::EAX => r::Eax,
-            doodoo::R9D => r::R9d,
-            doodoo::RAX => r::Rax,
-            doodoo::RBX => r::Rbx,
-            doodoo::RCX => r::Rcx,
-            doodoo::RDX => r::Rdx,
-            doodoo::RBP => r::Rbp,
-            doodoo::RSP => r::Rsp,
-            doodoo::RSI => r::Rsi,
-            doodoo::RDI => r::Rdi,
-            doodoo::R8 => r::R8,
-            doodoo::R9 => r::R9,
-            doodoo::R10 => r::R10,
-            doodoo::R11 => r::R11,
-            doodoo::R12 => r::R12,
-            doodoo::R13 => r::R13,
-            doodoo::R14 => r::R14,
-            doodoo::R15 => r::R15,
-            _ => bail!("doodoo {:?}", value),
+        match REGISTERS.iter().find(|&&(_, ir)| ir == value) {
+            Some(&(r, _)) => Ok(r),
+            None => bail!("register {:?} not supported", value),
+        }
+    }
+}
+
+impl From<Register> for iced_x86::Register {
+    fn from(value: Register) -> Self {
+        REGISTERS.iter().find(|&&(r, _)| r == value).unwrap().1
+    }
+}
+
+impl std::str::FromStr for Register {
+    type Err = anyhow::Error;
+
+    /// A register from its name in NASM, such as `r8d`
+    fn from_str(name: &str) -> Result<Self> {
+        REGISTERS
+            .iter()
+            .map(|&(r, _)| r)
+            .find(|r| r.to_string() == name)
+            .with_context(|| format!("no register named {}", name))
+    }
+}
+
+/// A memory operand, `[base + index * scale + displacement]`, or an address
+/// relative to the instruction's, `[rel displacement]`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Memory {
+    pub base: Option<Register>,
+    pub index: Option<Register>,
+    /// 1, 2, 4 or 8
+    pub scale: u8,
+    /// For a RIP-relative operand, the address it refers to
+    pub displacement: i64,
+    pub rip_relative: bool,
+    /// The width of the value it refers to in bytes, or 0 where only its
+    /// address is taken, as by `lea`
+    pub size: usize,
+}
+
+impl Memory {
+    /// `qword [base + displacement]`, as a86's `Offset`
+    pub fn offset(base: Register, displacement: i64) -> Self {
+        Self {
+            base: Some(base),
+            index: None,
+            scale: 1,
+            displacement,
+            rip_relative: false,
+            size: 8,
+        }
+    }
+
+    /// `qword [rel address]`
+    pub fn rel(address: Address) -> Self {
+        Self {
+            base: None,
+            index: None,
+            scale: 1,
+            displacement: address as i64,
+            rip_relative: true,
+            size: 8,
+        }
+    }
+
+    /// The address a RIP-relative operand refers to
+    pub fn target(&self) -> Option<Address> {
+        self.rip_relative.then_some(self.displacement as Address)
+    }
+
+    /// The operand as iced decoded it, if it's memory
+    fn decode(instruction: &iced_x86::Instruction) -> Result<Self> {
+        let register = |r: iced_x86::Register| -> Result<Option<Register>> {
+            match r {
+                iced_x86::Register::None => Ok(None),
+                r => Ok(Some(r.try_into()?)),
+            }
+        };
+        let size = instruction.memory_size().size();
+        // iced resolves RIP-relative displacements to the address itself
+        if instruction.is_ip_rel_memory_operand() {
+            return Ok(Self {
+                size,
+                ..Self::rel(instruction.ip_rel_memory_address())
+            });
+        }
+        Ok(Self {
+            base: register(instruction.memory_base())?,
+            index: register(instruction.memory_index())?,
+            scale: instruction.memory_index_scale() as u8,
+            displacement: instruction.memory_displacement64() as i64,
+            rip_relative: false,
+            size,
         })
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Arg {
+    /// The target of a branch
     Address(Address),
     Register(Register),
-    Offset(Register, i64),
-    Literal(u64),
+    Memory(Memory),
+    /// An immediate, as a signed value of the operation's width: `0xff` in
+    /// `mov al, 0xff` is -1, whichever way it's encoded
+    Literal(i64),
+}
+
+/// Memory operands in NASM syntax
+impl std::fmt::Display for Memory {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if let Some(address) = self.target() {
+            return write!(f, "[rel {:#x}]", address);
+        }
+        let mut terms = Vec::new();
+        terms.extend(self.base.map(|base| base.to_string()));
+        match (self.index, self.scale) {
+            (Some(index), 1) => terms.push(index.to_string()),
+            (Some(index), scale) => terms.push(format!("{}*{}", index, scale)),
+            (None, _) => {}
+        }
+        let mut out = terms.join(" + ");
+        match self.displacement {
+            0 if !out.is_empty() => {}
+            d if out.is_empty() => out = format!("{:#x}", d),
+            d if d < 0 => out += &format!(" - {:#x}", d.unsigned_abs()),
+            d => out += &format!(" + {:#x}", d),
+        }
+        write!(f, "[{}]", out)
+    }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Instruction {
     Add(Arg, Arg),
     Sub(Arg, Arg),
     And(Arg, Arg),
+    Or(Arg, Arg),
     Xor(Arg, Arg),
+    Not(Arg),
+    /// Also written `shl`, which is the same instruction
+    Sal(Arg, Arg),
+    Sar(Arg, Arg),
+    Shr(Arg, Arg),
     Mov(Arg, Arg),
+    /// Moves a byte or word, zero-extended
+    Movzx(Arg, Arg),
     Cmove(Arg, Arg),
+    Cmovne(Arg, Arg),
     Cmovl(Arg, Arg),
+    Cmovle(Arg, Arg),
+    Cmovg(Arg, Arg),
+    Cmovge(Arg, Arg),
     Cmp(Arg, Arg),
+    Test(Arg, Arg),
+    Sete(Arg),
+    Setne(Arg),
+    Setl(Arg),
+    Setle(Arg),
+    Setg(Arg),
+    Setge(Arg),
     Call(Address),
     Jmp(Arg),
     Jne(Arg),
     Je(Arg),
     Jl(Arg),
+    Jle(Arg),
     Jg(Arg),
+    Jge(Arg),
     Push(Arg),
     Pop(Arg),
     Lea(Arg, Arg),
     Ret,
 }
 
+impl std::fmt::Display for Register {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", format!("{:?}", self).to_lowercase())
+    }
+}
+
+/// Operands in NASM syntax
+impl std::fmt::Display for Arg {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            Arg::Address(address) => write!(f, "{:#x}", address),
+            Arg::Register(r) => write!(f, "{}", r),
+            Arg::Memory(memory) => write!(f, "{}", memory),
+            Arg::Literal(value) if value < 0 => write!(f, "-{:#x}", value.unsigned_abs()),
+            Arg::Literal(value) => write!(f, "{:#x}", value),
+        }
+    }
+}
+
+/// Instructions in NASM syntax
+impl std::fmt::Display for Instruction {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let operands = self.operands();
+        write!(f, "{}", self.mnemonic())?;
+        for (i, operand) in operands.iter().enumerate() {
+            write!(f, "{}", if i == 0 { " " } else { ", " })?;
+            // Memory operands need a size unless a register operand implies it
+            if let Arg::Memory(memory) = operand
+                && memory.size != 0
+                && !operands
+                    .iter()
+                    .any(|o| matches!(o, Arg::Register(r) if r.size() == memory.size))
+            {
+                match memory.size {
+                    1 => write!(f, "byte ")?,
+                    2 => write!(f, "word ")?,
+                    4 => write!(f, "dword ")?,
+                    _ => write!(f, "qword ")?,
+                }
+            }
+            write!(f, "{}", operand)?;
+        }
+        Ok(())
+    }
+}
+
+impl Instruction {
+    /// The instruction's name, as in a86 but lowercase
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Instruction::Add(..) => "add",
+            Instruction::Sub(..) => "sub",
+            Instruction::And(..) => "and",
+            Instruction::Or(..) => "or",
+            Instruction::Xor(..) => "xor",
+            Instruction::Not(_) => "not",
+            Instruction::Sal(..) => "sal",
+            Instruction::Sar(..) => "sar",
+            Instruction::Shr(..) => "shr",
+            Instruction::Mov(..) => "mov",
+            Instruction::Movzx(..) => "movzx",
+            Instruction::Cmove(..) => "cmove",
+            Instruction::Cmovne(..) => "cmovne",
+            Instruction::Cmovl(..) => "cmovl",
+            Instruction::Cmovle(..) => "cmovle",
+            Instruction::Cmovg(..) => "cmovg",
+            Instruction::Cmovge(..) => "cmovge",
+            Instruction::Cmp(..) => "cmp",
+            Instruction::Test(..) => "test",
+            Instruction::Sete(_) => "sete",
+            Instruction::Setne(_) => "setne",
+            Instruction::Setl(_) => "setl",
+            Instruction::Setle(_) => "setle",
+            Instruction::Setg(_) => "setg",
+            Instruction::Setge(_) => "setge",
+            Instruction::Call(_) => "call",
+            Instruction::Jmp(_) => "jmp",
+            Instruction::Jne(_) => "jne",
+            Instruction::Je(_) => "je",
+            Instruction::Jl(_) => "jl",
+            Instruction::Jle(_) => "jle",
+            Instruction::Jg(_) => "jg",
+            Instruction::Jge(_) => "jge",
+            Instruction::Push(_) => "push",
+            Instruction::Pop(_) => "pop",
+            Instruction::Lea(..) => "lea",
+            Instruction::Ret => "ret",
+        }
+    }
+
+    /// The instruction as an a86 s-expression, such as `(Mov 'rax 16)`, with
+    /// addresses shown as the labels `label` gives them where it can
+    pub fn to_a86(self, label: impl Fn(Address) -> Option<String>) -> String {
+        let mnemonic = self.mnemonic();
+        let mut out = format!("({}{}", mnemonic[..1].to_uppercase(), &mnemonic[1..]);
+        for operand in self.operands() {
+            out.push(' ');
+            out.push_str(&match operand {
+                Arg::Address(address) => match label(address) {
+                    Some(label) => format!("'{}", label),
+                    None => format!("#x{:x}", address),
+                },
+                Arg::Register(r) => format!("'{}", r),
+                Arg::Memory(memory) => match memory {
+                    Memory {
+                        base: Some(base),
+                        index: None,
+                        rip_relative: false,
+                        displacement,
+                        ..
+                    } => format!("(Offset '{} {})", base, displacement),
+                    // a86 refers to code by its label
+                    Memory {
+                        rip_relative: true,
+                        displacement,
+                        ..
+                    } => match label(displacement as Address) {
+                        Some(label) => format!("'{}", label),
+                        None => format!("#x{:x}", displacement),
+                    },
+                    // Base, index, scale and displacement
+                    Memory {
+                        base,
+                        index,
+                        scale,
+                        displacement,
+                        ..
+                    } => {
+                        let register = |r: Option<Register>| match r {
+                            Some(r) => format!("'{}", r),
+                            None => "#f".to_owned(),
+                        };
+                        format!(
+                            "(Mem {} {} {} {})",
+                            register(base),
+                            register(index),
+                            scale,
+                            displacement
+                        )
+                    }
+                },
+                Arg::Literal(value) => value.to_string(),
+            });
+        }
+        out.push(')');
+        out
+    }
+
+    pub fn operands(&self) -> Vec<Arg> {
+        match *self {
+            Instruction::Add(a, b)
+            | Instruction::Sub(a, b)
+            | Instruction::And(a, b)
+            | Instruction::Or(a, b)
+            | Instruction::Xor(a, b)
+            | Instruction::Sal(a, b)
+            | Instruction::Sar(a, b)
+            | Instruction::Shr(a, b)
+            | Instruction::Mov(a, b)
+            | Instruction::Movzx(a, b)
+            | Instruction::Cmove(a, b)
+            | Instruction::Cmovne(a, b)
+            | Instruction::Cmovl(a, b)
+            | Instruction::Cmovle(a, b)
+            | Instruction::Cmovg(a, b)
+            | Instruction::Cmovge(a, b)
+            | Instruction::Cmp(a, b)
+            | Instruction::Test(a, b)
+            | Instruction::Lea(a, b) => vec![a, b],
+            Instruction::Call(address) => vec![Arg::Address(address)],
+            Instruction::Not(a)
+            | Instruction::Sete(a)
+            | Instruction::Setne(a)
+            | Instruction::Setl(a)
+            | Instruction::Setle(a)
+            | Instruction::Setg(a)
+            | Instruction::Setge(a)
+            | Instruction::Jmp(a)
+            | Instruction::Jne(a)
+            | Instruction::Je(a)
+            | Instruction::Jl(a)
+            | Instruction::Jle(a)
+            | Instruction::Jg(a)
+            | Instruction::Jge(a)
+            | Instruction::Push(a)
+            | Instruction::Pop(a) => vec![a],
+            Instruction::Ret => vec![],
+        }
+    }
+}
+
+/// Operand `i` of `instruction`, whatever kind it is
+fn operand(instruction: &iced_x86::Instruction, i: u32) -> Result<Arg> {
+    Ok(match instruction.op_kind(i) {
+        OpKind::Register => Arg::Register(instruction.op_register(i).try_into()?),
+        OpKind::Memory => Arg::Memory(Memory::decode(instruction)?),
+        OpKind::NearBranch64 => Arg::Address(instruction.near_branch_target()),
+        // Immediates are signed, whether the instruction sign-extends them or
+        // they're as wide as the operation already
+        OpKind::Immediate8 => Arg::Literal((instruction.immediate8() as i8).into()),
+        OpKind::Immediate16 => Arg::Literal((instruction.immediate16() as i16).into()),
+        OpKind::Immediate32 => Arg::Literal((instruction.immediate32() as i32).into()),
+        OpKind::Immediate64 => Arg::Literal(instruction.immediate64() as i64),
+        OpKind::Immediate8to16 => Arg::Literal(instruction.immediate8to16().into()),
+        OpKind::Immediate8to32 => Arg::Literal(instruction.immediate8to32().into()),
+        OpKind::Immediate8to64 => Arg::Literal(instruction.immediate8to64()),
+        OpKind::Immediate32to64 => Arg::Literal(instruction.immediate32to64()),
+        kind => bail!("operand kind {:?} not implemented", kind),
+    })
+}
+
+/// `instruction` in NASM syntax, for error messages
+fn nasm(instruction: &iced_x86::Instruction) -> String {
+    let mut out = String::new();
+    NasmFormatter::new().format(instruction, &mut out);
+    out
+}
+
+/// Instructions are decoded by mnemonic, whatever their encoding, with their
+/// operands taken as they come (see `operand`)
 impl TryFrom<iced_x86::Instruction> for Instruction {
     type Error = anyhow::Error;
 
     fn try_from(value: iced_x86::Instruction) -> std::result::Result<Self, Self::Error> {
+        use Instruction as I;
+        use iced_x86::Mnemonic as M;
+
+        let operands = (0..value.op_count())
+            .map(|i| operand(&value, i))
+            .collect::<Result<Vec<Arg>>>()
+            .with_context(|| format!("unsupported operand in `{}`", nasm(&value)))?;
+        Ok(match (value.mnemonic(), operands.as_slice()) {
+            (M::Add, &[a, b]) => I::Add(a, b),
+            (M::Sub, &[a, b]) => I::Sub(a, b),
+            (M::And, &[a, b]) => I::And(a, b),
+            (M::Or, &[a, b]) => I::Or(a, b),
+            (M::Xor, &[a, b]) => I::Xor(a, b),
+            (M::Not, &[a]) => I::Not(a),
+            // `shl` and `sal` are the same instruction
+            (M::Shl | M::Sal, &[a, b]) => I::Sal(a, b),
+            (M::Sar, &[a, b]) => I::Sar(a, b),
+            (M::Shr, &[a, b]) => I::Shr(a, b),
+            (M::Mov, &[a, b]) => I::Mov(a, b),
+            (M::Movzx, &[a, b]) => I::Movzx(a, b),
+            (M::Cmove, &[a, b]) => I::Cmove(a, b),
+            (M::Cmovne, &[a, b]) => I::Cmovne(a, b),
+            (M::Cmovl, &[a, b]) => I::Cmovl(a, b),
+            (M::Cmovle, &[a, b]) => I::Cmovle(a, b),
+            (M::Cmovg, &[a, b]) => I::Cmovg(a, b),
+            (M::Cmovge, &[a, b]) => I::Cmovge(a, b),
+            (M::Cmp, &[a, b]) => I::Cmp(a, b),
+            (M::Test, &[a, b]) => I::Test(a, b),
+            (M::Sete, &[a]) => I::Sete(a),
+            (M::Setne, &[a]) => I::Setne(a),
+            (M::Setl, &[a]) => I::Setl(a),
+            (M::Setle, &[a]) => I::Setle(a),
+            (M::Setg, &[a]) => I::Setg(a),
+            (M::Setge, &[a]) => I::Setge(a),
+            (M::Call, &[Arg::Address(target)]) => I::Call(target),
+            (M::Jmp, &[a]) => I::Jmp(a),
+            (M::Jne, &[a]) => I::Jne(a),
+            (M::Je, &[a]) => I::Je(a),
+            (M::Jl, &[a]) => I::Jl(a),
+            (M::Jle, &[a]) => I::Jle(a),
+            (M::Jg, &[a]) => I::Jg(a),
+            (M::Jge, &[a]) => I::Jge(a),
+            (M::Push, &[a]) => I::Push(a),
+            (M::Pop, &[a]) => I::Pop(a),
+            (M::Lea, &[a, b @ Arg::Memory(_)]) => I::Lea(a, b),
+            (M::Ret, []) => I::Ret,
+            (mnemonic, operands) => bail!(
+                "instruction `{}` ({:?} with operands {:?}) not implemented",
+                nasm(&value),
+                mnemonic,
+                operands
+            ),
+        })
+    }
+}
+
+/// Opcode encodings of a two-operand ALU instruction, in the order
+/// `rm64, r64`; `rm32, r32`; `rm64, imm8`; `rm64, imm32`; `rm32, imm8`; `rm32, imm32`
+type AluCodes = [Code; 6];
+
+const ADD_CODES: AluCodes = [
+    Code::Add_rm64_r64,
+    Code::Add_rm32_r32,
+    Code::Add_rm64_imm8,
+    Code::Add_rm64_imm32,
+    Code::Add_rm32_imm8,
+    Code::Add_rm32_imm32,
+];
+const SUB_CODES: AluCodes = [
+    Code::Sub_rm64_r64,
+    Code::Sub_rm32_r32,
+    Code::Sub_rm64_imm8,
+    Code::Sub_rm64_imm32,
+    Code::Sub_rm32_imm8,
+    Code::Sub_rm32_imm32,
+];
+const AND_CODES: AluCodes = [
+    Code::And_rm64_r64,
+    Code::And_rm32_r32,
+    Code::And_rm64_imm8,
+    Code::And_rm64_imm32,
+    Code::And_rm32_imm8,
+    Code::And_rm32_imm32,
+];
+const OR_CODES: AluCodes = [
+    Code::Or_rm64_r64,
+    Code::Or_rm32_r32,
+    Code::Or_rm64_imm8,
+    Code::Or_rm64_imm32,
+    Code::Or_rm32_imm8,
+    Code::Or_rm32_imm32,
+];
+const XOR_CODES: AluCodes = [
+    Code::Xor_rm64_r64,
+    Code::Xor_rm32_r32,
+    Code::Xor_rm64_imm8,
+    Code::Xor_rm64_imm32,
+    Code::Xor_rm32_imm8,
+    Code::Xor_rm32_imm32,
+];
+const CMP_CODES: AluCodes = [
+    Code::Cmp_rm64_r64,
+    Code::Cmp_rm32_r32,
+    Code::Cmp_rm64_imm8,
+    Code::Cmp_rm64_imm32,
+    Code::Cmp_rm32_imm8,
+    Code::Cmp_rm32_imm32,
+];
+
+/// Encodes a register-destination ALU instruction, picking the shortest
+/// immediate form like nasm does
+fn encode_alu(codes: AluCodes, dst: Arg, src: Arg) -> Result<iced_x86::Instruction> {
+    let [
+        rm64_r64,
+        rm32_r32,
+        rm64_imm8,
+        rm64_imm32,
+        rm32_imm8,
+        rm32_imm32,
+    ] = codes;
+    let Arg::Register(dst) = dst else {
+        bail!("unsupported destination operand {:?}", dst)
+    };
+    ensure!(
+        dst.size() >= 4,
+        "no {}-byte form of {:?}",
+        dst.size(),
+        rm64_r64.mnemonic()
+    );
+    let dst = iced_x86::Register::from(dst);
+    let wide = dst.is_gpr64();
+
+    Ok(match src {
+        Arg::Register(src) => iced_x86::Instruction::with2(
+            if wide { rm64_r64 } else { rm32_r32 },
+            dst,
+            iced_x86::Register::from(src),
+        )?,
+        Arg::Literal(lit) => {
+            let imm = if wide {
+                i32::try_from(lit).ok()
+            } else {
+                i32::try_from(lit)
+                    .ok()
+                    .or(u32::try_from(lit).ok().map(|imm| imm as i32))
+            };
+            let imm = imm.context("immediate does not fit in 32 bits")?;
+            let code = match (wide, i8::try_from(imm).is_ok()) {
+                (true, true) => rm64_imm8,
+                (true, false) => rm64_imm32,
+                (false, true) => rm32_imm8,
+                (false, false) => rm32_imm32,
+            };
+            iced_x86::Instruction::with2(code, dst, imm)?
+        }
+        _ => bail!("unsupported source operand {:?}", src),
+    })
+}
+
+/// Encodes a shift of `register` by `count`, given the instruction's
+/// encodings for 64- and 32-bit registers
+fn encode_shift(codes: [Code; 2], register: Register, count: i64) -> Result<iced_x86::Instruction> {
+    ensure!(
+        register.size() >= 4,
+        "no {}-byte form of {:?}",
+        register.size(),
+        codes[0].mnemonic()
+    );
+    let register = iced_x86::Register::from(register);
+    let code = if register.is_gpr64() {
+        codes[0]
+    } else {
+        codes[1]
+    };
+    let count = u32::try_from(count).ok().filter(|&n| n < 64);
+    Ok(iced_x86::Instruction::with2(
+        code,
+        register,
+        count.context("shift count out of range")?,
+    )?)
+}
+
+/// Encodes a conditional move into a 64-bit register
+fn encode_cmov(code: Code, dst: Arg, src: Arg) -> Result<iced_x86::Instruction> {
+    let Arg::Register(dst) = dst else {
+        bail!("unsupported destination operand {:?}", dst)
+    };
+    let dst = iced_x86::Register::from(dst);
+    Ok(match src {
+        Arg::Register(src) => {
+            iced_x86::Instruction::with2(code, dst, iced_x86::Register::from(src))?
+        }
+        src => iced_x86::Instruction::with2(code, dst, memory_operand(src)?)?,
+    })
+}
+
+/// Of encodings for operands 64, 32, 16 and 8 bits wide, the one for operands
+/// `size` bytes wide
+fn sized_code(size: usize, codes: [Code; 4]) -> Result<Code> {
+    Ok(match size {
+        8 => codes[0],
+        4 => codes[1],
+        2 => codes[2],
+        1 => codes[3],
+        _ => bail!("no {}-byte form of {:?}", size, codes[0].mnemonic()),
+    })
+}
+
+/// `lit` as an immediate `size` bytes wide (up to 4), taken as signed or
+/// unsigned like nasm does
+fn narrow_immediate(lit: i64, size: usize) -> Result<u32> {
+    let bits = 8 * size as u32;
+    let (min, max) = (-1 << (bits - 1), (1 << bits) - 1);
+    ensure!(
+        (min..=max).contains(&lit),
+        "immediate {:#x} does not fit in {} bits",
+        lit,
+        bits
+    );
+    Ok((lit as u64 & max as u64) as u32)
+}
+
+fn memory_operand(arg: Arg) -> Result<MemoryOperand> {
+    let Arg::Memory(memory) = arg else {
+        bail!("{:?} is not a memory operand", arg)
+    };
+    let register = |r: Option<Register>| r.map_or(iced_x86::Register::None, Into::into);
+    Ok(match memory.target() {
+        Some(address) => MemoryOperand::with_base_displ(iced_x86::Register::RIP, address as i64),
+        None => MemoryOperand::with_base_index_scale_displ_size(
+            register(memory.base),
+            register(memory.index),
+            memory.scale.into(),
+            memory.displacement,
+            if memory.displacement == 0 { 0 } else { 1 },
+        ),
+    })
+}
+
+fn branch_target(arg: Arg) -> Result<Address> {
+    match arg {
+        Arg::Address(address) => Ok(address),
+        _ => bail!("{:?} is not a branch target", arg),
+    }
+}
+
+impl TryFrom<Instruction> for iced_x86::Instruction {
+    type Error = anyhow::Error;
+
+    fn try_from(value: Instruction) -> std::result::Result<Self, Self::Error> {
+        use iced_x86::Instruction as I;
+
         Ok(match value {
-            t if t.code() == Code::Push_r64 => {
-                Instruction::Push(Arg::Register(t.op_register(0).try_into()?))
+            Instruction::Add(dst, src) => encode_alu(ADD_CODES, dst, src)?,
+            Instruction::Sub(dst, src) => encode_alu(SUB_CODES, dst, src)?,
+            Instruction::And(dst, src) => encode_alu(AND_CODES, dst, src)?,
+            Instruction::Or(dst, src) => encode_alu(OR_CODES, dst, src)?,
+            Instruction::Xor(dst, src) => encode_alu(XOR_CODES, dst, src)?,
+            Instruction::Cmp(dst, src) => encode_alu(CMP_CODES, dst, src)?,
+            Instruction::Test(Arg::Register(dst), src) => {
+                ensure!(dst.size() >= 4, "no {}-byte form of test", dst.size());
+                let dst = iced_x86::Register::from(dst);
+                let wide = dst.is_gpr64();
+                match src {
+                    Arg::Register(src) => I::with2(
+                        if wide {
+                            Code::Test_rm64_r64
+                        } else {
+                            Code::Test_rm32_r32
+                        },
+                        dst,
+                        iced_x86::Register::from(src),
+                    )?,
+                    Arg::Literal(lit) => I::with2(
+                        if wide {
+                            Code::Test_rm64_imm32
+                        } else {
+                            Code::Test_rm32_imm32
+                        },
+                        dst,
+                        i32::try_from(lit).context("immediate does not fit in 32 bits")?,
+                    )?,
+                    _ => bail!("unsupported source operand {:?}", src),
+                }
             }
-            t if t.code() == Code::Pop_r64 => {
-                Instruction::Pop(Arg::Register(t.op_register(0).try_into()?))
+            Instruction::Not(Arg::Register(r)) => {
+                ensure!(r.size() >= 4, "no {}-byte form of not", r.size());
+                let r = iced_x86::Register::from(r);
+                I::with1(
+                    if r.is_gpr64() {
+                        Code::Not_rm64
+                    } else {
+                        Code::Not_rm32
+                    },
+                    r,
+                )?
             }
-            t if t.code() == Code::Jmp_rm64 => {
-                Instruction::Jmp(Arg::Register(t.op_register(0).try_into()?))
+            Instruction::Sal(Arg::Register(r), Arg::Literal(n)) => {
+                encode_shift([Code::Shl_rm64_imm8, Code::Shl_rm32_imm8], r, n)?
             }
-            t if t.code() == Code::Jmp_rel8_64 => {
-                Instruction::Jmp(Arg::Address(t.memory_displacement64()))
+            Instruction::Sar(Arg::Register(r), Arg::Literal(n)) => {
+                encode_shift([Code::Sar_rm64_imm8, Code::Sar_rm32_imm8], r, n)?
             }
-            t if t.code() == Code::Je_rel32_64 || t.code() == Code::Je_rel8_64 => {
-                Instruction::Je(Arg::Address(t.memory_displacement64()))
+            Instruction::Shr(Arg::Register(r), Arg::Literal(n)) => {
+                encode_shift([Code::Shr_rm64_imm8, Code::Shr_rm32_imm8], r, n)?
             }
-            t if t.code() == Code::Jl_rel32_64 || t.code() == Code::Jl_rel8_64 => {
-                Instruction::Jl(Arg::Address(t.memory_displacement64()))
+            Instruction::Movzx(Arg::Register(dst), src) => {
+                let dst = iced_x86::Register::from(dst);
+                let size = match src {
+                    Arg::Register(src) => iced_x86::Register::from(src).size(),
+                    Arg::Memory(memory) => memory.size,
+                    _ => bail!("unsupported source operand {:?}", src),
+                };
+                let code = match (dst.size(), size) {
+                    (8, 1) => Code::Movzx_r64_rm8,
+                    (4, 1) => Code::Movzx_r32_rm8,
+                    (2, 1) => Code::Movzx_r16_rm8,
+                    (8, 2) => Code::Movzx_r64_rm16,
+                    (4, 2) => Code::Movzx_r32_rm16,
+                    _ => bail!("movzx from {:?} is not an extension", src),
+                };
+                match src {
+                    Arg::Register(src) => I::with2(code, dst, iced_x86::Register::from(src))?,
+                    src => I::with2(code, dst, memory_operand(src)?)?,
+                }
             }
-            t if t.code() == Code::Jg_rel32_64 || t.code() == Code::Jg_rel8_64 => {
-                Instruction::Jg(Arg::Address(t.memory_displacement64()))
+            Instruction::Sete(Arg::Register(r)) => {
+                I::with1(Code::Sete_rm8, iced_x86::Register::from(r))?
             }
-            t if t.code() == Code::Jne_rel32_64 || t.code() == Code::Jne_rel8_64 => {
-                Instruction::Jne(Arg::Address(t.memory_displacement64()))
+            Instruction::Setne(Arg::Register(r)) => {
+                I::with1(Code::Setne_rm8, iced_x86::Register::from(r))?
             }
-            t if t.code() == Code::Call_rel32_64 => Instruction::Call(t.memory_displacement64()),
-            t if t.code() == Code::Cmp_rm64_imm8 => Instruction::Cmp(
-                Arg::Register(t.op_register(0).try_into()?),
-                Arg::Literal(t.immediate(1)),
-            ),
-            t if t.code() == Code::Add_rm64_imm8
-                || t.code() == Code::Add_rm64_imm32
-                || t.code() == Code::Add_rm32_imm8
-                || t.code() == Code::Add_rm32_imm32 =>
-            {
-                Instruction::Add(
-                    Arg::Register(t.op_register(0).try_into()?),
-                    Arg::Literal(t.immediate(1)),
-                )
+            Instruction::Setl(Arg::Register(r)) => {
+                I::with1(Code::Setl_rm8, iced_x86::Register::from(r))?
             }
-            t if t.code() == Code::Add_rm64_r64 || t.code() == Code::Add_rm32_r32 => {
-                Instruction::Add(
-                    Arg::Register(t.op_register(0).try_into()?),
-                    Arg::Register(t.op_register(1).try_into()?),
-                )
+            Instruction::Setle(Arg::Register(r)) => {
+                I::with1(Code::Setle_rm8, iced_x86::Register::from(r))?
             }
-            t if t.code() == Code::Sub_rm64_imm8
-                || t.code() == Code::Sub_rm64_imm32
-                || t.code() == Code::Sub_rm32_imm8
-                || t.code() == Code::Sub_rm32_imm32 =>
-            {
-                Instruction::Sub(
-                    Arg::Register(t.op_register(0).try_into()?),
-                    Arg::Literal(t.immediate(1)),
-                )
+            Instruction::Setg(Arg::Register(r)) => {
+                I::with1(Code::Setg_rm8, iced_x86::Register::from(r))?
             }
-            t if t.code() == Code::Sub_rm64_r64 || t.code() == Code::Sub_rm32_r32 => {
-                Instruction::Sub(
-                    Arg::Register(t.op_register(0).try_into()?),
-                    Arg::Register(t.op_register(1).try_into()?),
-                )
+            Instruction::Setge(Arg::Register(r)) => {
+                I::with1(Code::Setge_rm8, iced_x86::Register::from(r))?
             }
-            t if t.code() == Code::And_rm64_imm8
-                || t.code() == Code::And_rm64_imm32
-                || t.code() == Code::And_rm32_imm8
-                || t.code() == Code::And_rm32_imm32 =>
-            {
-                Instruction::And(
-                    Arg::Register(t.op_register(0).try_into()?),
-                    Arg::Literal(t.immediate(1)),
-                )
+            Instruction::Mov(Arg::Register(dst), Arg::Register(src)) => {
+                ensure!(
+                    dst.size() == src.size(),
+                    "mov between registers of different sizes"
+                );
+                let code = sized_code(
+                    dst.size(),
+                    [
+                        Code::Mov_rm64_r64,
+                        Code::Mov_rm32_r32,
+                        Code::Mov_rm16_r16,
+                        Code::Mov_rm8_r8,
+                    ],
+                )?;
+                I::with2(
+                    code,
+                    iced_x86::Register::from(dst),
+                    iced_x86::Register::from(src),
+                )?
             }
-            t if t.code() == Code::And_rm64_r64 || t.code() == Code::And_rm32_r32 => {
-                Instruction::And(
-                    Arg::Register(t.op_register(0).try_into()?),
-                    Arg::Register(t.op_register(1).try_into()?),
-                )
+            Instruction::Mov(Arg::Register(dst), Arg::Literal(lit)) => {
+                let size = dst.size();
+                let dst = iced_x86::Register::from(dst);
+                if size != 8 {
+                    let code = sized_code(
+                        size,
+                        [
+                            Code::Mov_r64_imm64,
+                            Code::Mov_r32_imm32,
+                            Code::Mov_r16_imm16,
+                            Code::Mov_r8_imm8,
+                        ],
+                    )?;
+                    I::with2(code, dst, narrow_immediate(lit, size)?)?
+                } else if let Ok(imm) = u32::try_from(lit) {
+                    // nasm turns `mov rax, imm32` into the shorter, zero-extending `mov eax, imm32`
+                    I::with2(Code::Mov_r32_imm32, dst.full_register32(), imm)?
+                } else if let Ok(imm) = i32::try_from(lit) {
+                    I::with2(Code::Mov_rm64_imm32, dst, imm)?
+                } else {
+                    I::with2(Code::Mov_r64_imm64, dst, lit as u64)?
+                }
             }
-            t if t.code() == Code::Xor_rm64_imm8
-                || t.code() == Code::Xor_rm64_imm32
-                || t.code() == Code::Xor_rm32_imm8
-                || t.code() == Code::Xor_rm32_imm32 =>
-            {
-                Instruction::Xor(
-                    Arg::Register(t.op_register(0).try_into()?),
-                    Arg::Literal(t.immediate(1)),
-                )
+            Instruction::Mov(Arg::Register(dst), src @ Arg::Memory(_)) => {
+                let code = sized_code(
+                    dst.size(),
+                    [
+                        Code::Mov_r64_rm64,
+                        Code::Mov_r32_rm32,
+                        Code::Mov_r16_rm16,
+                        Code::Mov_r8_rm8,
+                    ],
+                )?;
+                I::with2(code, iced_x86::Register::from(dst), memory_operand(src)?)?
             }
-            t if t.code() == Code::Xor_rm64_r64 || t.code() == Code::Xor_rm32_r32 => {
-                Instruction::Xor(
-                    Arg::Register(t.op_register(0).try_into()?),
-                    Arg::Register(t.op_register(1).try_into()?),
-                )
+            Instruction::Mov(dst @ Arg::Memory(memory), Arg::Literal(lit)) => {
+                let code = sized_code(
+                    memory.size,
+                    [
+                        Code::Mov_rm64_imm32,
+                        Code::Mov_rm32_imm32,
+                        Code::Mov_rm16_imm16,
+                        Code::Mov_rm8_imm8,
+                    ],
+                )?;
+                let dst = memory_operand(dst)?;
+                match memory.size {
+                    // The 64-bit form sign-extends a 32-bit immediate
+                    8 => I::with2(
+                        code,
+                        dst,
+                        i32::try_from(lit).context("immediate does not fit in 32 bits")?,
+                    )?,
+                    size => I::with2(code, dst, narrow_immediate(lit, size)?)?,
+                }
             }
-            t if t.code() == Code::Mov_rm64_r64
-                || t.code() == Code::Mov_r64_rm64
-                || t.code() == Code::Mov_r32_rm32
-                || t.code() == Code::Mov_rm32_r32 =>
-            {
-                Instruction::Mov(
-                    match t.op0_kind() {
-                        OpKind::Memory => Arg::Offset(
-                            t.memory_base().try_into()?,
-                            t.memory_displacement64() as i64,
-                        ),
-                        OpKind::Register => Arg::Register(t.op_register(0).try_into()?),
-                        _ => bail!("incorrect operand kind found for move dst"),
-                    },
-                    match t.op1_kind() {
-                        OpKind::Memory => Arg::Offset(
-                            t.memory_base().try_into()?,
-                            t.memory_displacement64() as i64,
-                        ),
-                        OpKind::Register => Arg::Register(t.op_register(1).try_into()?),
-                        _ => bail!("incorrect operand kind found for move src"),
-                    },
-                )
+            Instruction::Mov(dst @ Arg::Memory(_), Arg::Register(src)) => {
+                let code = sized_code(
+                    src.size(),
+                    [
+                        Code::Mov_rm64_r64,
+                        Code::Mov_rm32_r32,
+                        Code::Mov_rm16_r16,
+                        Code::Mov_rm8_r8,
+                    ],
+                )?;
+                I::with2(code, memory_operand(dst)?, iced_x86::Register::from(src))?
             }
-            t if t.code() == Code::Mov_r32_imm32 => Instruction::Mov(
-                Arg::Register(t.op_register(0).try_into()?),
-                Arg::Literal(t.immediate(1)),
-            ),
-            t if t.code() == Code::Cmove_r64_rm64 => Instruction::Cmove(
-                Arg::Register(t.op_register(0).try_into()?),
-                Arg::Register(t.op_register(1).try_into()?),
-            ),
-            t if t.code() == Code::Cmovl_r64_rm64 => Instruction::Cmovl(
-                Arg::Register(t.op_register(0).try_into()?),
-                Arg::Register(t.op_register(1).try_into()?),
-            ),
-            t if t.code() == Code::Lea_r64_m => Instruction::Lea(
-                Arg::Register(t.op_register(0).try_into()?),
-                Arg::Address(t.memory_displacement64()),
-            ),
-            t if t.code() == Code::Retnq => Instruction::Ret,
-            t => bail!("instruction code {:#?} not implmented", t.code()),
+            Instruction::Cmove(dst, src) => encode_cmov(Code::Cmove_r64_rm64, dst, src)?,
+            Instruction::Cmovne(dst, src) => encode_cmov(Code::Cmovne_r64_rm64, dst, src)?,
+            Instruction::Cmovl(dst, src) => encode_cmov(Code::Cmovl_r64_rm64, dst, src)?,
+            Instruction::Cmovle(dst, src) => encode_cmov(Code::Cmovle_r64_rm64, dst, src)?,
+            Instruction::Cmovg(dst, src) => encode_cmov(Code::Cmovg_r64_rm64, dst, src)?,
+            Instruction::Cmovge(dst, src) => encode_cmov(Code::Cmovge_r64_rm64, dst, src)?,
+            Instruction::Call(address) => I::with_branch(Code::Call_rel32_64, address)?,
+            Instruction::Jmp(Arg::Register(r)) => {
+                I::with1(Code::Jmp_rm64, iced_x86::Register::from(r))?
+            }
+            // Branches are encoded in their long form; the block encoder
+            // shortens them where the target is close enough
+            Instruction::Jmp(target) => I::with_branch(Code::Jmp_rel32_64, branch_target(target)?)?,
+            Instruction::Jne(target) => I::with_branch(Code::Jne_rel32_64, branch_target(target)?)?,
+            Instruction::Je(target) => I::with_branch(Code::Je_rel32_64, branch_target(target)?)?,
+            Instruction::Jl(target) => I::with_branch(Code::Jl_rel32_64, branch_target(target)?)?,
+            Instruction::Jg(target) => I::with_branch(Code::Jg_rel32_64, branch_target(target)?)?,
+            Instruction::Jle(target) => I::with_branch(Code::Jle_rel32_64, branch_target(target)?)?,
+            Instruction::Jge(target) => I::with_branch(Code::Jge_rel32_64, branch_target(target)?)?,
+            Instruction::Push(Arg::Register(r)) => {
+                I::with1(Code::Push_r64, iced_x86::Register::from(r))?
+            }
+            Instruction::Pop(Arg::Register(r)) => {
+                I::with1(Code::Pop_r64, iced_x86::Register::from(r))?
+            }
+            Instruction::Lea(Arg::Register(dst), src) => I::with2(
+                Code::Lea_r64_m,
+                iced_x86::Register::from(dst),
+                memory_operand(src)?,
+            )?,
+            Instruction::Ret => I::with(Code::Retnq),
+            t => bail!("cannot encode instruction {:?}", t),
         })
     }
 }
@@ -249,6 +1093,10 @@ pub struct Program {
     /// A mapping between instructions' memory addresses
     /// and their index in the `instructions` vector
     memory_map: BiMap<Address, usize>,
+    /// The byte ranges between instructions that no code reaches
+    unreachable: Vec<Range<Address>>,
+    /// Where each function starts, `entry` among them
+    functions: BTreeSet<Address>,
 
     address_to_symbols: HashMap<Address, HashSet<String>>,
     symbols_to_address: HashMap<String, Address>,
@@ -263,6 +1111,17 @@ impl Program {
         self.instructions.as_slice()
     }
 
+    /// The byte ranges between the program's instructions that aren't
+    /// reachable, such as padding or data
+    pub fn unreachable(&self) -> &[Range<Address>] {
+        &self.unreachable
+    }
+
+    /// Where each function, closure body and the program itself starts
+    pub fn functions(&self) -> &BTreeSet<Address> {
+        &self.functions
+    }
+
     pub fn address_to_index(&self, address: Address) -> Option<usize> {
         self.memory_map.get_by_left(&address).copied()
     }
@@ -282,18 +1141,105 @@ impl Program {
         self.symbols_to_address.get(symbol).copied()
     }
 
+    /// The first, alphabetically, of the symbols at `address`
+    pub fn label(&self, address: Address) -> Option<String> {
+        self.address_to_symbols.get(&address)?.iter().min().cloned()
+    }
+
+    /// Every named symbol, ordered by address and then name
+    pub fn symbols(&self) -> Vec<(&str, Address)> {
+        let mut symbols: Vec<_> = self
+            .symbols_to_address
+            .iter()
+            .filter(|(symbol, _)| !symbol.is_empty())
+            .map(|(symbol, &address)| (symbol.as_str(), address))
+            .collect();
+        symbols.sort_by_key(|&(symbol, address)| (address, symbol));
+        symbols
+    }
+
+    /// The program in an ELF or Mach-O file, whichever it is, or in NASM
+    /// assembly if the file is named `.s` or `.asm`
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        if path.extension().is_some_and(|e| e == "s" || e == "asm") {
+            let source = fs::read_to_string(path).context("Failed to read assembly file")?;
+            return nasm::parse(&source).context("Failed to parse assembly file");
+        }
+        let bytes = fs::read(path).context("Failed to read file")?;
+        Self::from_bytes(&bytes)
+    }
+
+    /// The program in the contents of an ELF or Mach-O file
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if macho::is_macho(bytes) {
+            Self::from_macho_bytes(bytes)
+        } else {
+            Self::from_elf_bytes(bytes)
+        }
+    }
+
+    /// The program in a Mach-O object (not a linked executable), given as its
+    /// contents.
+    /// Symbols are named as on Linux, without their leading `_`.
+    pub fn from_macho_bytes(bytes: &[u8]) -> Result<Self> {
+        let macho = macho::parse(bytes).context("Failed to parse Mach-O file")?;
+        Self::from_text(&macho.text, macho.text_address, macho.symbols, &[])
+    }
+
     pub fn from_elf_file<P: AsRef<Path>>(path: P) -> Result<Self> {
         let elf_bytes = fs::read(path).context("Failed to read ELF file")?;
+        Self::from_elf_bytes(&elf_bytes)
+    }
+
+    /// The program in an ELF executable or relocatable object, given as its
+    /// contents. In an object, sections are laid out one after another from
+    /// address 0, .text first, and undefined symbols (the runtime's functions)
+    /// are given addresses after them, which `.rela.text`'s relocations are
+    /// resolved against.
+    pub fn from_elf_bytes(elf_bytes: &[u8]) -> Result<Self> {
         let elf_file =
-            ElfBytes::<AnyEndian>::minimal_parse(&elf_bytes).context("Failed to parse ELF file")?;
+            ElfBytes::<AnyEndian>::minimal_parse(elf_bytes).context("Failed to parse ELF file")?;
+        let relocatable = elf_file.ehdr.e_type == ET_REL;
+
+        // Get the text section
+        let (section_headers, section_names) = elf_file
+            .section_headers_with_strtab()
+            .context("Failed to parse section table in ELF file")?;
+        let (section_headers, section_names) = (
+            section_headers.context("ELF file did not have a section table")?,
+            section_names.context("ELF file did not have section names")?,
+        );
+        let sections: Vec<SectionHeader> = section_headers.iter().collect();
+        let text_index = sections
+            .iter()
+            .position(|section| section_names.get(section.sh_name as usize).ok() == Some(".text"))
+            .context("ELF file did not have a .text segment")?;
+        let text_section = sections[text_index];
+
+        // Where each section starts
+        let mut bases: Vec<Address> = sections.iter().map(|section| section.sh_addr).collect();
+        let mut end = text_section.sh_addr + text_section.sh_size;
+        if relocatable {
+            for (i, section) in sections.iter().enumerate() {
+                if i != text_index && section.sh_flags & SHF_ALLOC as u64 != 0 {
+                    bases[i] = end.next_multiple_of(section.sh_addralign.max(1));
+                    end = bases[i] + section.sh_size;
+                }
+            }
+        }
+        let mut next_external = end.next_multiple_of(16);
 
         // Construct the symbol table
         let (parsing_table, string_table) = elf_file
             .symbol_table()
             .context("Failed to parse symbol table in ELF file")?
             .context("ELF file did not have a symbol table")?;
-        let mut address_to_symbols: HashMap<Address, HashSet<String>> = HashMap::new();
-        let mut symbols_to_address: HashMap<String, Address> = HashMap::new();
+        let mut symbols = Vec::new();
+        // The address of each symbol, by index
+        let mut symbol_addresses = Vec::new();
+        // The size of `entry`, and where the functions in .text start
+        let (mut entry_size, mut functions) = (0, Vec::new());
         for symbol in parsing_table {
             let identifier = string_table
                 .get(
@@ -304,22 +1250,28 @@ impl Program {
                 )
                 .context("Failed to lookup name associated with symbol")?;
 
-            let address: Address = symbol.st_value;
-
-            symbols_to_address.insert(identifier.to_owned(), address);
-            address_to_symbols
-                .entry(address)
-                .and_modify(|s| {
-                    s.insert(identifier.to_owned());
-                })
-                .or_insert(HashSet::from_iter(iter::once(identifier.to_owned())));
+            let address: Address = match symbol.st_shndx {
+                _ if !relocatable => symbol.st_value,
+                SHN_UNDEF if !identifier.is_empty() => {
+                    next_external += 16;
+                    next_external - 16
+                }
+                section if (section as usize) < bases.len() => {
+                    bases[section as usize] + symbol.st_value
+                }
+                // Absolute symbols
+                _ => symbol.st_value,
+            };
+            symbol_addresses.push(address);
+            symbols.push((identifier.to_owned(), address));
+            if identifier == "entry" {
+                entry_size = symbol.st_size;
+            }
+            if symbol.st_symtype() == STT_FUNC && symbol.st_shndx as usize == text_index {
+                functions.push(address);
+            }
         }
 
-        // Get the text section
-        let text_section = elf_file
-            .section_header_by_name(".text")
-            .context("Failed to parse section table in ELF file")?
-            .context("ELF file did not have a .text segment")?;
         let text_section_start = text_section.sh_addr;
         let (code_bytes, compression_header) = elf_file
             .section_data(&text_section)
@@ -329,57 +1281,681 @@ impl Program {
             "ELF file had compression header"
         );
 
-        // Get the location of the entry point (relative to start of text section)
+        // Apply relocations to the code, as a linker would
+        let mut code_bytes = code_bytes.to_vec();
+        for section in &sections {
+            if section.sh_type != SHT_RELA || section.sh_info as usize != text_index {
+                continue;
+            }
+            let relas = elf_file
+                .section_data_as_relas(section)
+                .context("Failed to parse relocations for .text")?;
+            for rela in relas {
+                let symbol = *symbol_addresses
+                    .get(rela.r_sym as usize)
+                    .context("relocation refers to a missing symbol")?;
+                let value = symbol.wrapping_add_signed(rela.r_addend);
+                let place = text_section_start + rela.r_offset;
+                let bytes = match rela.r_type {
+                    R_X86_64_PC32 | R_X86_64_PLT32 => {
+                        (value.wrapping_sub(place) as u32).to_le_bytes().to_vec()
+                    }
+                    R_X86_64_32 | R_X86_64_32S => (value as u32).to_le_bytes().to_vec(),
+                    R_X86_64_64 => value.to_le_bytes().to_vec(),
+                    t => bail!("relocation type {} not implemented", t),
+                };
+                let offset = rela.r_offset as usize;
+                code_bytes
+                    .get_mut(offset..offset + bytes.len())
+                    .context("relocation outside of .text")?
+                    .copy_from_slice(&bytes);
+            }
+        }
+
+        // The program's code ends where `entry` says it does, or else at the
+        // next function, which belongs to the runtime
+        let entry = symbols
+            .iter()
+            .find(|(identifier, _)| identifier == "entry")
+            .map(|&(_, address)| address)
+            .context("text section did not have entry symbol")?;
+        let code_end = match entry_size {
+            0 => functions.iter().copied().filter(|&f| f > entry).min(),
+            size => Some(entry + size),
+        };
+        if let Some(code_end) = code_end {
+            code_bytes.truncate(code_end.saturating_sub(text_section_start) as usize);
+        }
+
+        // The functions in .text other than `entry` are the runtime's
+        let runtime: Vec<Address> = functions.into_iter().filter(|&f| f != entry).collect();
+        Self::from_text(&code_bytes, text_section_start, symbols, &runtime)
+    }
+
+    /// The program in `code`, the contents of a text section starting at
+    /// `text_section_start`, given the binary's symbols. The program is the
+    /// code reachable from `entry` and the labels after it, except the
+    /// `runtime`'s functions.
+    pub fn from_text(
+        code_bytes: &[u8],
+        text_section_start: Address,
+        symbols: Vec<(String, Address)>,
+        runtime: &[Address],
+    ) -> Result<Self> {
+        let mut address_to_symbols: HashMap<Address, HashSet<String>> = HashMap::new();
+        let mut symbols_to_address: HashMap<String, Address> = HashMap::new();
+        for (identifier, address) in symbols {
+            address_to_symbols
+                .entry(address)
+                .and_modify(|s| {
+                    s.insert(identifier.clone());
+                })
+                .or_insert(HashSet::from_iter(iter::once(identifier.clone())));
+            symbols_to_address.insert(identifier, address);
+        }
+
         let &entry_point = symbols_to_address
             .get("entry")
-            .context(".text section of ELF file did not have entry symbol")?;
-        let entry_point_in_text: usize = (entry_point - text_section_start)
-            .try_into()
-            .context("entry point in text section too large")?;
-        let program_end = symbols_to_address
-            .get("err")
-            .context("could not find err label")? + 10;
-
-        // Disassemble the text section into instructions
-        let mut decoder = Decoder::with_ip(
-            64,
-            &code_bytes[entry_point_in_text..],
-            entry_point,
-            DecoderOptions::NONE,
+            .context("text section did not have entry symbol")?;
+        let text_end = text_section_start + code_bytes.len() as Address;
+        ensure!(
+            (text_section_start..text_end).contains(&entry_point),
+            "entry point outside of text section"
         );
-        let instrs: Vec<_> = decoder.iter().collect();
 
-        let a86_instrs = instrs
-            .iter()
-            .filter(|&x| x.ip() <= program_end)
-            .map(|&x86_instr| x86_instr.try_into())
-            .collect::<Vec<Result<Instruction>>>();
-
-        let mut instructions = Vec::with_capacity(a86_instrs.len());
-
-        for (i, x) in a86_instrs.iter().enumerate() {
-            match x {
-                Ok(t) => instructions.push(*t),
-                Err(e) => bail!(
-                    "errored at instruction {i} ({:#x?}) with error {e}",
-                    instrs[i]
-                ),
-            };
-        }
+        // Disassemble the code reachable from `entry` and the labels after it
+        let roots = iter::once(entry_point).chain(
+            address_to_symbols
+                .keys()
+                .copied()
+                .filter(|&address| address > entry_point && address < text_end),
+        );
+        let raise_error = symbols_to_address.get("raise_error").copied();
+        let instrs = traverse(code_bytes, text_section_start, roots, runtime, raise_error);
 
+        let mut instructions = Vec::with_capacity(instrs.len());
         let mut memory_map = BiMap::new();
-        for (i, instr) in instrs.iter().enumerate() {
-            memory_map.insert(instr.ip(), i);
+        for (i, x86_instr) in instrs.iter().enumerate() {
+            let instruction = Instruction::try_from(*x86_instr).with_context(|| {
+                format!(
+                    "errored at instruction {:#x} ({})",
+                    x86_instr.ip(),
+                    x86_instr
+                )
+            })?;
+            instructions.push(instruction);
+            memory_map.insert(x86_instr.ip(), i);
+        }
+
+        // The gaps between the instructions
+        let mut unreachable = Vec::new();
+        for pair in instrs.windows(2) {
+            if pair[0].next_ip() < pair[1].ip() {
+                unreachable.push(pair[0].next_ip()..pair[1].ip());
+            }
         }
 
+        let functions = functions(&instructions, entry_point, |address| {
+            memory_map.contains_left(&address)
+        });
+
         Ok(Self {
             entry_point,
 
             instructions,
             memory_map,
+            unreachable,
+            functions,
 
             address_to_symbols,
             symbols_to_address,
         })
     }
 }
+
+/// The instructions in `code`, which starts at `start`, reachable from the
+/// `roots`, in order. Control is followed through branches, calls (which are
+/// assumed to return, except those to `raise_error`) and code addresses taken
+/// with `lea`, but not into the `runtime`'s functions.
+fn traverse(
+    code: &[u8],
+    start: Address,
+    roots: impl Iterator<Item = Address>,
+    runtime: &[Address],
+    raise_error: Option<Address>,
+) -> Vec<iced_x86::Instruction> {
+    let mut instrs = BTreeMap::new();
+    let mut pending: Vec<Address> = roots.collect();
+    while let Some(address) = pending.pop() {
+        if instrs.contains_key(&address) || runtime.contains(&address) {
+            continue;
+        }
+        let Some(bytes) = address
+            .checked_sub(start)
+            .and_then(|offset| code.get(offset as usize..))
+            .filter(|bytes| !bytes.is_empty())
+        else {
+            continue;
+        };
+        let x = Decoder::with_ip(64, bytes, address, DecoderOptions::NONE).decode();
+        instrs.insert(address, x);
+
+        match x.flow_control() {
+            FlowControl::UnconditionalBranch
+            | FlowControl::ConditionalBranch
+            | FlowControl::Call => pending.push(x.near_branch_target()),
+            // Code whose address is taken: a return label or a closure's body.
+            // Other RIP-relative operands are loads and stores of data.
+            FlowControl::Next
+                if x.mnemonic() == Mnemonic::Lea
+                    && (start..start + code.len() as Address)
+                        .contains(&x.ip_rel_memory_address()) =>
+            {
+                pending.push(x.ip_rel_memory_address())
+            }
+            _ => {}
+        }
+        let falls_through = match x.flow_control() {
+            FlowControl::Next | FlowControl::ConditionalBranch | FlowControl::IndirectCall => true,
+            FlowControl::Call => Some(x.near_branch_target()) != raise_error,
+            _ => false,
+        };
+        if falls_through {
+            pending.push(x.next_ip());
+        }
+    }
+    instrs.into_values().collect()
+}
+
+/// Where functions start among `instructions`: at `entry`, at the targets of
+/// calls, and at code whose address is taken other than to be pushed as a
+/// return address, as a closure's is. `is_code` says whether an address is
+/// one of the instructions'.
+fn functions(
+    instructions: &[Instruction],
+    entry: Address,
+    is_code: impl Fn(Address) -> bool,
+) -> BTreeSet<Address> {
+    let mut functions = BTreeSet::from([entry]);
+    for (i, instruction) in instructions.iter().enumerate() {
+        let function = match *instruction {
+            Instruction::Call(target) => target,
+            Instruction::Lea(Arg::Register(r), Arg::Memory(memory))
+                if instructions.get(i + 1) != Some(&Instruction::Push(Arg::Register(r))) =>
+            {
+                match memory.target() {
+                    Some(target) => target,
+                    None => continue,
+                }
+            }
+            _ => continue,
+        };
+        if is_code(function) {
+            functions.insert(function);
+        }
+    }
+    functions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relocatable_calls_resolve_to_symbols() {
+        let bytes = include_bytes!("../test-programs/read-byte.o");
+        let program = Program::from_elf_bytes(bytes).unwrap();
+
+        let calls: Vec<_> = program
+            .instructions()
+            .iter()
+            .filter_map(|instruction| match instruction {
+                Instruction::Call(address) => Some(*address),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            calls,
+            vec![
+                program.symbol_to_address("read_byte").unwrap(),
+                program.symbol_to_address("raise_error").unwrap()
+            ]
+        );
+        // The runtime's functions get addresses of their own, after the code
+        let err = program.symbol_to_address("err").unwrap();
+        assert!(calls.iter().all(|&call| call > err));
+        assert_ne!(calls[0], calls[1]);
+        assert_eq!(program.entry_point(), 0);
+    }
+
+    #[test]
+    fn program_is_the_reachable_code() {
+        use super::{Arg::*, Instruction::*, Register::*};
+        use crate::{
+            assembler::Assembler,
+            decompiler,
+            elf_writer::{push_epilogue, push_prologue},
+        };
+
+        let mut asm = Assembler::new();
+        let raise_error = 0x8000;
+        push_prologue(&mut asm);
+        asm.push(Mov(Register(Rax), Literal(0x50)));
+        push_epilogue(&mut asm);
+        // Code between the epilogue and `err`, as a function would be
+        asm.bind("helper").unwrap();
+        asm.push(Mov(Register(Rax), Literal(0x10)));
+        asm.push(Ret);
+        asm.bind("err").unwrap();
+        asm.push(Mov(Register(R15), Register(Rsp)));
+        asm.push(Call(raise_error));
+        let assembly = asm.assemble(0x1000).unwrap();
+
+        // Whatever follows the call to `raise_error` isn't the program's
+        let mut code = assembly.code;
+        code.extend([0x0f, 0x0b, 0xff, 0xff]);
+        let mut symbols: Vec<_> = assembly.labels.into_iter().collect();
+        symbols.push(("raise_error".to_owned(), raise_error));
+        let program = Program::from_text(&code, 0x1000, symbols, &[]).unwrap();
+
+        let instructions = program.instructions();
+        assert_eq!(instructions.len(), 13);
+        assert_eq!(instructions.last(), Some(&Call(raise_error)));
+        let err = program.symbol_to_address("err").unwrap();
+        assert_eq!(program.address_to_index(err), Some(11));
+        assert_eq!(
+            decompiler::parse(&program).unwrap().to_string(),
+            "#lang racket\n5"
+        );
+    }
+
+    #[test]
+    fn data_loaded_relative_to_rip_is_not_code() {
+        use super::{Arg::*, Instruction::*, Register::*};
+        use crate::assembler::Assembler;
+
+        let mut asm = Assembler::new();
+        let data = asm.label("data");
+        asm.bind("entry").unwrap();
+        asm.push(Mov(Register(Rax), Memory(super::Memory::rel(data))));
+        asm.push(Ret);
+        // Data in the text section, which happens to decode as a jump
+        asm.bind("data").unwrap();
+        asm.push(Jmp(Address(data)));
+        let assembly = asm.assemble(0x1000).unwrap();
+        // Without a symbol of its own, which would make it a root
+        let symbols = vec![("entry".to_owned(), assembly.labels["entry"])];
+        let program = Program::from_text(&assembly.code, 0x1000, symbols, &[]).unwrap();
+
+        assert_eq!(program.instructions().len(), 2);
+        assert_eq!(program.address_to_index(assembly.labels["data"]), None);
+    }
+
+    #[test]
+    fn functions_are_found_before_entry() {
+        use super::{Arg::*, Instruction::*, Register::*};
+        use crate::{assembler::Assembler, disasm};
+
+        let mut asm = Assembler::new();
+        let (double, lambda) = (asm.label("double"), asm.label("lambda"));
+        asm.bind("double").unwrap();
+        asm.push(Add(Register(Rax), Register(Rax)));
+        asm.push(Ret);
+        // Dead code, never jumped to
+        asm.push(Mov(Register(Rax), Literal(1)));
+        asm.bind("entry").unwrap();
+        asm.push(Call(double));
+        asm.push(Lea(Register(Rax), Memory(super::Memory::rel(lambda))));
+        asm.push(Mov(Memory(super::Memory::offset(Rbx, 0)), Register(Rax)));
+        asm.push(Ret);
+        asm.bind("lambda").unwrap();
+        asm.push(Ret);
+        let assembly = asm.assemble(0x1000).unwrap();
+        let symbols = assembly.labels.clone().into_iter().collect();
+        let program = Program::from_text(&assembly.code, 0x1000, symbols, &[]).unwrap();
+
+        let labels = &assembly.labels;
+        assert_eq!(
+            program.functions(),
+            &BTreeSet::from([labels["double"], labels["entry"], labels["lambda"]])
+        );
+        assert_eq!(program.instructions().len(), 7);
+        assert_eq!(program.address_to_index(labels["entry"]), Some(2));
+        let dead = 0x1004..labels["entry"];
+        assert_eq!(program.unreachable(), &[dead]);
+        let listing = disasm::disassemble(&program, disasm::Syntax::Nasm);
+        assert!(
+            listing.contains("; 0x1004: 5 unreachable byte(s)\nentry:\n"),
+            "{}",
+            listing
+        );
+    }
+
+    #[test]
+    fn registers_of_every_width() {
+        for (register, iced) in REGISTERS {
+            assert_eq!(Register::try_from(iced).unwrap(), register);
+            assert_eq!(register.to_string().parse::<Register>().unwrap(), register);
+            assert_eq!(register.full().size(), 8);
+        }
+        assert_eq!(Register::Al.full(), Register::Rax);
+        assert_eq!(Register::R8d.full(), Register::R8);
+        assert!(Register::Eax.zero_extends() && !Register::Ax.zero_extends());
+        assert!(Register::Ah.aliases(Register::Eax));
+        assert!(!Register::Ah.aliases(Register::Al));
+        assert!(!Register::R9d.aliases(Register::R8));
+
+        // mov r8d, 5
+        let x86 = Decoder::new(64, &[0x41, 0xb8, 5, 0, 0, 0], DecoderOptions::NONE).decode();
+        assert_eq!(
+            Instruction::try_from(x86).unwrap(),
+            Instruction::Mov(Arg::Register(Register::R8d), Arg::Literal(5))
+        );
+    }
+
+    #[test]
+    fn memory_operands_decode_for_every_instruction() {
+        let code = [
+            0x4a, 0x8b, 0x44, 0xc3, 0x10, // mov rax, [rbx + r8*8 + 16]
+            0x48, 0x03, 0x43, 0x08, // add rax, [rbx + 8]
+            0x48, 0x83, 0x3b, 0x00, // cmp qword [rbx], 0
+            0x48, 0x29, 0x4c, 0x24, 0xf8, // sub [rsp - 8], rcx
+            0x48, 0x8d, 0x05, 0x10, 0x00, 0x00, 0x00, // lea rax, [rel 0x1029]
+            0xc6, 0x03, 0x01, // mov byte [rbx], 1
+            0x83, 0x38, 0x00, // cmp dword [rax], 0
+            0x0f, 0xb7, 0x03, // movzx eax, word [rbx]
+            0x8a, 0x03, // mov al, [rbx]
+        ];
+        let mut decoder = Decoder::with_ip(64, &code, 0x1000, DecoderOptions::NONE);
+        let listing: Vec<String> = decoder
+            .iter()
+            .map(|x86| Instruction::try_from(x86).unwrap().to_string())
+            .collect();
+        assert_eq!(
+            listing,
+            [
+                "mov rax, [rbx + r8*8 + 0x10]",
+                "add rax, [rbx + 0x8]",
+                "cmp qword [rbx], 0x0",
+                "sub [rsp - 0x8], rcx",
+                "lea rax, [rel 0x1029]",
+                "mov byte [rbx], 0x1",
+                "cmp dword [rax], 0x0",
+                "movzx eax, word [rbx]",
+                "mov al, [rbx]",
+            ]
+        );
+
+        let memory = Memory {
+            base: Some(Register::Rbx),
+            index: Some(Register::R8),
+            scale: 8,
+            displacement: 16,
+            rip_relative: false,
+            size: 8,
+        };
+        let mov = Instruction::Mov(Arg::Register(Register::Rax), Arg::Memory(memory));
+        let encoded = iced_x86::Instruction::try_from(mov).unwrap();
+        assert_eq!(Instruction::try_from(encoded).unwrap(), mov);
+        assert_eq!(mov.to_a86(|_| None), "(Mov 'rax (Mem 'rbx 'r8 8 16))");
+    }
+
+    #[test]
+    fn decodes_every_instruction() {
+        use super::{Arg::*, Instruction::*, Register::*};
+        let rbx = |displacement| Arg::Memory(super::Memory::offset(Rbx, displacement));
+
+        // Encodings as nasm and llvm-mc produce them, at address 0x1000
+        let cases: &[(&[u8], Instruction)] = &[
+            (&[0x48, 0x83, 0xc8, 0x08], Or(Register(Rax), Literal(8))),
+            (&[0x48, 0x09, 0xd8], Or(Register(Rax), Register(Rbx))),
+            (
+                &[0x48, 0x0d, 0, 0x10, 0, 0],
+                Or(Register(Rax), Literal(0x1000)),
+            ),
+            (
+                &[0x48, 0x05, 0, 0x10, 0, 0],
+                Add(Register(Rax), Literal(0x1000)),
+            ),
+            (&[0x31, 0xc0], Xor(Register(Eax), Register(Eax))),
+            (&[0x48, 0xf7, 0xd0], Not(Register(Rax))),
+            (&[0x48, 0xc1, 0xe0, 0x04], Sal(Register(Rax), Literal(4))),
+            (&[0x48, 0xd1, 0xe0], Sal(Register(Rax), Literal(1))),
+            (&[0x48, 0xc1, 0xf8, 0x04], Sar(Register(Rax), Literal(4))),
+            (&[0x49, 0xc1, 0xe9, 0x3f], Shr(Register(R9), Literal(63))),
+            (&[0x48, 0x39, 0xd8], Cmp(Register(Rax), Register(Rbx))),
+            (
+                &[0x48, 0x3d, 0, 0x01, 0, 0],
+                Cmp(Register(Rax), Literal(0x100)),
+            ),
+            (
+                &[0x48, 0xc7, 0x43, 0x08, 0x18, 0, 0, 0],
+                Mov(rbx(8), Literal(0x18)),
+            ),
+            (&[0x48, 0x89, 0x03], Mov(rbx(0), Register(Rax))),
+            (
+                &[0x48, 0xb8, 0x89, 0x67, 0x45, 0x23, 0x01, 0, 0, 0],
+                Mov(Register(Rax), Literal(0x1_2345_6789)),
+            ),
+            (&[0x0f, 0xb6, 0xc0], Movzx(Register(Eax), Register(Al))),
+            (
+                &[0x48, 0x0f, 0xb6, 0x03],
+                Movzx(
+                    Register(Rax),
+                    Arg::Memory(super::Memory {
+                        size: 1,
+                        ..super::Memory::offset(Rbx, 0)
+                    }),
+                ),
+            ),
+            (&[0x0f, 0x94, 0xc0], Sete(Register(Al))),
+            (&[0x0f, 0x95, 0xc0], Setne(Register(Al))),
+            (&[0x0f, 0x9c, 0xc0], Setl(Register(Al))),
+            (&[0x0f, 0x9e, 0xc0], Setle(Register(Al))),
+            (&[0x0f, 0x9f, 0xc0], Setg(Register(Al))),
+            (&[0x0f, 0x9d, 0xc0], Setge(Register(Al))),
+            (&[0x48, 0x85, 0xc0], Test(Register(Rax), Register(Rax))),
+            (
+                &[0x48, 0xa9, 0x07, 0, 0, 0],
+                Test(Register(Rax), Literal(7)),
+            ),
+            (
+                &[0x49, 0xf7, 0xc1, 0x01, 0, 0, 0],
+                Test(Register(R9), Literal(1)),
+            ),
+            (
+                &[0x49, 0x0f, 0x45, 0xc1],
+                Cmovne(Register(Rax), Register(R9)),
+            ),
+            (
+                &[0x49, 0x0f, 0x4f, 0xc1],
+                Cmovg(Register(Rax), Register(R9)),
+            ),
+            (
+                &[0x49, 0x0f, 0x4d, 0xc1],
+                Cmovge(Register(Rax), Register(R9)),
+            ),
+            (
+                &[0x49, 0x0f, 0x4e, 0xc1],
+                Cmovle(Register(Rax), Register(R9)),
+            ),
+            (&[0xe9, 0xfb, 0x01, 0, 0], Jmp(Address(0x1200))),
+            (&[0x7d, 0x0e], Jge(Address(0x1010))),
+            (&[0x0f, 0x8e, 0xfa, 0x01, 0, 0], Jle(Address(0x1200))),
+        ];
+        for &(bytes, expected) in cases {
+            let x86 = Decoder::with_ip(64, bytes, 0x1000, DecoderOptions::NONE).decode();
+            let decoded = Instruction::try_from(x86)
+                .unwrap_or_else(|e| panic!("{} didn't decode: {}", expected, e));
+            assert_eq!(decoded, expected, "{:02x?}", bytes);
+
+            // And it encodes back to something that decodes the same
+            let mut encoder = iced_x86::Encoder::new(64);
+            let mut encoded = iced_x86::Instruction::try_from(expected).unwrap();
+            encoded.set_ip(0x1000);
+            encoder.encode(&encoded, 0x1000).unwrap();
+            let code = encoder.take_buffer();
+            let x86 = Decoder::with_ip(64, &code, 0x1000, DecoderOptions::NONE).decode();
+            assert_eq!(Instruction::try_from(x86).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn word_and_byte_forms_round_trip() {
+        use super::{Arg::*, Instruction::*, Register::*};
+        let rbx = |size, displacement| {
+            Arg::Memory(super::Memory {
+                size,
+                ..super::Memory::offset(Rbx, displacement)
+            })
+        };
+
+        // Encodings as llvm-mc produces them
+        let cases: &[(&[u8], Instruction)] = &[
+            (&[0x0f, 0xb7, 0x03], Movzx(Register(Eax), rbx(2, 0))),
+            (&[0x0f, 0xb6, 0x03], Movzx(Register(Eax), rbx(1, 0))),
+            (&[0x66, 0x0f, 0xb6, 0x03], Movzx(Register(Ax), rbx(1, 0))),
+            (&[0xc6, 0x03, 0x01], Mov(rbx(1, 0), Literal(1))),
+            (&[0x66, 0xc7, 0x03, 0x01, 0x00], Mov(rbx(2, 0), Literal(1))),
+            (&[0xc7, 0x03, 0x01, 0, 0, 0], Mov(rbx(4, 0), Literal(1))),
+            (
+                &[0x48, 0xc7, 0x03, 0x01, 0, 0, 0],
+                Mov(rbx(8, 0), Literal(1)),
+            ),
+            (&[0x66, 0x89, 0xd8], Mov(Register(Ax), Register(Bx))),
+            (&[0x88, 0xd8], Mov(Register(Al), Register(Bl))),
+            (&[0x66, 0xb8, 0x05, 0x00], Mov(Register(Ax), Literal(5))),
+            (&[0xb0, 0x05], Mov(Register(Al), Literal(5))),
+            (&[0xc6, 0x03, 0xff], Mov(rbx(1, 0), Literal(-1))),
+            (
+                &[0x66, 0xc7, 0x43, 0x02, 0xfe, 0xff],
+                Mov(rbx(2, 2), Literal(-2)),
+            ),
+            (
+                &[0xc7, 0x03, 0xff, 0xff, 0xff, 0xff],
+                Mov(rbx(4, 0), Literal(-1)),
+            ),
+            (
+                &[0x48, 0xc7, 0x03, 0xff, 0xff, 0xff, 0xff],
+                Mov(rbx(8, 0), Literal(-1)),
+            ),
+            (&[0x41, 0xb0, 0x7f], Mov(Register(R8b), Literal(0x7f))),
+            (&[0x66, 0x8b, 0x03], Mov(Register(Ax), rbx(2, 0))),
+            (&[0x8a, 0x03], Mov(Register(Al), rbx(1, 0))),
+            (&[0x66, 0x89, 0x03], Mov(rbx(2, 0), Register(Ax))),
+            (&[0x88, 0x03], Mov(rbx(1, 0), Register(Al))),
+        ];
+        for &(bytes, expected) in cases {
+            let x86 = Decoder::with_ip(64, bytes, 0x1000, DecoderOptions::NONE).decode();
+            assert_eq!(Instruction::try_from(x86).unwrap(), expected);
+
+            let mut encoder = iced_x86::Encoder::new(64);
+            let encoded = iced_x86::Instruction::try_from(expected).unwrap();
+            encoder.encode(&encoded, 0x1000).unwrap();
+            assert_eq!(encoder.take_buffer(), bytes, "{}", expected);
+        }
+
+        let encode = |instruction| iced_x86::Instruction::try_from(instruction);
+        assert!(encode(Mov(Register(Al), Literal(0x100))).is_err());
+        assert!(encode(Mov(Register(Ax), Register(Bl))).is_err());
+        assert!(encode(Add(Register(Ax), Literal(1))).is_err());
+    }
+
+    #[test]
+    fn unsupported_instructions_are_named() {
+        // movsx rax, al
+        let x86 = Decoder::new(64, &[0x48, 0x0f, 0xbe, 0xc0], DecoderOptions::NONE).decode();
+        let error = Instruction::try_from(x86).unwrap_err().to_string();
+        assert!(error.contains("`movsx rax,al`"), "{}", error);
+        assert!(error.contains("Movsx with operands [Register(Rax), Register(Al)]"));
+    }
+
+    #[test]
+    fn decoding_agrees_with_iced_formatter() {
+        let mut formatter = NasmFormatter::new();
+        let options = formatter.options_mut();
+        options.set_space_after_operand_separator(true);
+        options.set_space_between_memory_add_operators(true);
+        options.set_hex_prefix("0x");
+        options.set_hex_suffix("");
+        options.set_uppercase_hex(false);
+        options.set_small_hex_numbers_in_decimal(false);
+        options.set_branch_leading_zeros(false);
+        options.set_displacement_leading_zeros(false);
+        options.set_leading_zeros(false);
+        options.set_show_branch_size(false);
+        options.set_signed_immediate_operands(true);
+        options.set_rip_relative_addresses(false);
+
+        let code: &[&[u8]] = &[
+            &[0x48, 0x83, 0xc4, 0xf8],                   // add rsp, -8
+            &[0x48, 0x83, 0xf8, 0xff],                   // cmp rax, -1
+            &[0x83, 0xf8, 0xff],                         // cmp eax, -1
+            &[0x48, 0x3d, 0x00, 0x00, 0x00, 0x80],       // cmp rax, -0x80000000
+            &[0x48, 0xc7, 0xc0, 0xf0, 0xff, 0xff, 0xff], // mov rax, -16
+            &[0x48, 0xb8, 0xf0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff], // mov rax, -16
+            &[0x48, 0x8b, 0x43, 0xf8],                   // mov rax, [rbx - 8]
+            &[0x48, 0xc7, 0x43, 0x08, 0xff, 0xff, 0xff, 0xff], // mov qword [rbx + 8], -1
+            &[0x4a, 0x8b, 0x44, 0xc3, 0x10],             // mov rax, [rbx + r8*8 + 0x10]
+            &[0xc6, 0x43, 0x01, 0xff],                   // mov byte [rbx + 1], -1
+            &[0xb8, 0xff, 0xff, 0xff, 0xff],             // mov eax, -1
+            &[0x3d, 0x00, 0x00, 0x00, 0x80],             // cmp eax, -0x80000000
+            &[0x66, 0xb8, 0xfe, 0xff],                   // mov ax, -2
+            &[0x66, 0x83, 0x38, 0x00],                   // cmp word [rax], 0
+            &[0x0f, 0xb6, 0x43, 0xf8],                   // movzx eax, byte [rbx - 8]
+            &[0x48, 0x0f, 0xb7, 0x03],                   // movzx rax, word [rbx]
+            &[0x74, 0xfe],                               // je to itself
+            &[0xeb, 0x80],                               // jmp backwards
+            &[0x0f, 0x8c, 0x00, 0x00, 0x01, 0x00],       // jl forwards
+            &[0xe8, 0xfb, 0xef, 0xff, 0xff],             // call backwards
+            &[0x48, 0xc1, 0xf8, 0x04],                   // sar rax, 4
+            &[0x49, 0xf7, 0xc1, 0x01, 0x00, 0x00, 0x00], // test r9, 1
+        ];
+        for bytes in code {
+            let x86 = Decoder::with_ip(64, bytes, 0x1000, DecoderOptions::NONE).decode();
+            let mut expected = String::new();
+            formatter.format(&x86, &mut expected);
+            let decoded = Instruction::try_from(x86).unwrap();
+            assert_eq!(decoded.to_string(), expected, "{:02x?}", bytes);
+        }
+    }
+
+    #[test]
+    fn immediates_keep_their_sign() {
+        use super::{Arg::*, Instruction::*, Register::*};
+
+        let decode = |bytes: &[u8]| {
+            let x86 = Decoder::with_ip(64, bytes, 0x1000, DecoderOptions::NONE).decode();
+            Instruction::try_from(x86).unwrap()
+        };
+        assert_eq!(
+            decode(&[0x48, 0x83, 0xc4, 0xf8]),
+            Add(Register(Rsp), Literal(-8))
+        );
+        assert_eq!(
+            decode(&[0x48, 0x83, 0xf8, 0xff]),
+            Cmp(Register(Rax), Literal(-1))
+        );
+        assert_eq!(
+            decode(&[0x48, 0xb8, 0xf0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]),
+            Mov(Register(Rax), Literal(-16))
+        );
+        // The same, whether the immediate is as wide as the operation or not
+        assert_eq!(
+            decode(&[0xb8, 0xff, 0xff, 0xff, 0xff]),
+            Mov(Register(Eax), Literal(-1))
+        );
+        assert_eq!(
+            decode(&[0x3d, 0xff, 0xff, 0xff, 0xff]),
+            Cmp(Register(Eax), Literal(-1))
+        );
+        assert_eq!(decode(&[0x83, 0xf8, 0xff]), Cmp(Register(Eax), Literal(-1)));
+        assert_eq!(decode(&[0xb0, 0x80]), Mov(Register(Al), Literal(-0x80)));
+        assert_eq!(decode(&[0xeb, 0x80]), Jmp(Address(0x1002 - 0x80)));
+        assert_eq!(
+            decode(&[0xe9, 0xfb, 0x01, 0x00, 0x00]),
+            Jmp(Address(0x1200))
+        );
+        assert_eq!(decode(&[0xe8, 0xfb, 0xef, 0xff, 0xff]), Call(0));
+    }
+}
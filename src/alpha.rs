@@ -0,0 +1,314 @@
+use bimap::BiMap;
+
+use crate::{
+    loot::{Defn, Expr, Id, Names, Pattern, Program, name},
+    pretty::DEFAULT_WIDTH,
+};
+
+/*
+  Alpha-equivalence of Loot programs: two programs are equivalent if they are
+  structurally equal up to a consistent renaming of `Id`s. Bound variables must
+  be bound by corresponding binders; free variables (and the names of
+  definitions) must map one-to-one. Definitions may appear in any order, since
+  they are all in scope in each other and in the main expression.
+*/
+
+/// The first place two programs differ
+#[derive(Debug, Clone, PartialEq)]
+pub struct Difference {
+    /// Steps from the root of the program to the differing subterms
+    pub path: Vec<String>,
+    /// The differing subterms, as source with each program's names
+    pub left: String,
+    pub right: String,
+}
+
+impl std::fmt::Display for Difference {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "at {}: `{}` differs from `{}`",
+            self.path.join("/"),
+            self.left,
+            self.right
+        )
+    }
+}
+
+impl std::error::Error for Difference {}
+
+#[derive(Clone, Default)]
+struct Differ {
+    /// Pairs of binders in scope, innermost last
+    scope: Vec<(Id, Id)>,
+    /// Free variables and definitions, which must correspond one-to-one
+    free: BiMap<Id, Id>,
+    path: Vec<String>,
+    /// The names to show the left and right programs' subterms with
+    names: [Names; 2],
+}
+
+type Diff = Result<(), Difference>;
+
+impl Differ {
+    fn differ(&self, left: impl ToString, right: impl ToString) -> Diff {
+        Err(Difference {
+            path: self.path.clone(),
+            left: left.to_string(),
+            right: right.to_string(),
+        })
+    }
+
+    fn differ_exprs(&self, a: &Expr, b: &Expr) -> Diff {
+        let [left, right] = &self.names;
+        self.differ(
+            a.to_doc(left).render(DEFAULT_WIDTH),
+            b.to_doc(right).render(DEFAULT_WIDTH),
+        )
+    }
+
+    fn at(&mut self, step: impl Into<String>, f: impl FnOnce(&mut Self) -> Diff) -> Diff {
+        self.path.push(step.into());
+        f(self)?;
+        self.path.pop();
+        Ok(())
+    }
+
+    fn var(&mut self, a: Id, b: Id) -> Diff {
+        // The innermost binder of either variable decides what it refers to
+        let bound = self.scope.iter().rev().find(|&&(x, y)| x == a || y == b);
+        let same = match bound {
+            Some(&pair) => pair == (a, b),
+            None => match (self.free.get_by_left(&a), self.free.get_by_right(&b)) {
+                (None, None) => {
+                    self.free.insert(a, b);
+                    true
+                }
+                (Some(&mapped), _) => mapped == b,
+                (None, Some(_)) => false,
+            },
+        };
+        if same {
+            Ok(())
+        } else {
+            self.differ(name(&self.names[0], a), name(&self.names[1], b))
+        }
+    }
+
+    /// Compares `a` and `b` with `binders` in scope
+    fn scoped(&mut self, binders: &[(Id, Id)], f: impl FnOnce(&mut Self) -> Diff) -> Diff {
+        let depth = self.scope.len();
+        self.scope.extend_from_slice(binders);
+        let result = f(self);
+        self.scope.truncate(depth);
+        result
+    }
+
+    fn params(&mut self, a: &[Id], b: &[Id], f: impl FnOnce(&mut Self) -> Diff) -> Diff {
+        if a.len() != b.len() {
+            return self.at("params", |d| {
+                d.differ(
+                    format!("{} parameters", a.len()),
+                    format!("{} parameters", b.len()),
+                )
+            });
+        }
+        let binders: Vec<_> = a.iter().copied().zip(b.iter().copied()).collect();
+        self.scoped(&binders, f)
+    }
+
+    /// Compares two patterns, collecting the variables they bind
+    fn pattern(&mut self, a: &Pattern, b: &Pattern, binders: &mut Vec<(Id, Id)>) -> Diff {
+        match (a, b) {
+            (Pattern::Var(a), Pattern::Var(b)) => binders.push((*a, *b)),
+            (Pattern::Literal(a), Pattern::Literal(b)) if a == b => {}
+            (Pattern::Box(a), Pattern::Box(b)) => self.at("box", |d| d.pattern(a, b, binders))?,
+            (Pattern::Cons(a1, a2), Pattern::Cons(b1, b2))
+            | (Pattern::Conj(a1, a2), Pattern::Conj(b1, b2)) => {
+                self.at("left", |d| d.pattern(a1, b1, binders))?;
+                self.at("right", |d| d.pattern(a2, b2, binders))?;
+            }
+            _ => return self.differ(a.to_source(&self.names[0]), b.to_source(&self.names[1])),
+        }
+        Ok(())
+    }
+
+    fn expr(&mut self, a: &Expr, b: &Expr) -> Diff {
+        match (a, b) {
+            (Expr::Literal(x), Expr::Literal(y)) if x == y => Ok(()),
+            (Expr::Op(x), Expr::Op(y)) if x.name() == y.name() => {
+                for (i, (x, y)) in x.operands().into_iter().zip(y.operands()).enumerate() {
+                    self.at(format!("arg{}", i + 1), |d| d.expr(x, y))?;
+                }
+                Ok(())
+            }
+            (Expr::If(a1, a2, a3), Expr::If(b1, b2, b3)) => {
+                self.at("test", |d| d.expr(a1, b1))?;
+                self.at("then", |d| d.expr(a2, b2))?;
+                self.at("else", |d| d.expr(a3, b3))
+            }
+            (Expr::Begin(xs), Expr::Begin(ys)) if xs.len() == ys.len() => {
+                for (i, (x, y)) in xs.iter().zip(ys).enumerate() {
+                    self.at(format!("expr{}", i + 1), |d| d.expr(x, y))?;
+                }
+                Ok(())
+            }
+            (Expr::Let(x, a1, a2), Expr::Let(y, b1, b2)) => {
+                self.at("rhs", |d| d.expr(a1, b1))?;
+                self.scoped(&[(*x, *y)], |d| d.at("body", |d| d.expr(a2, b2)))
+            }
+            (Expr::Var(x), Expr::Var(y)) => self.var(*x, *y),
+            (Expr::App(a, xs), Expr::App(b, ys)) if xs.len() == ys.len() => {
+                self.at("proc", |d| d.expr(a, b))?;
+                for (i, (x, y)) in xs.iter().zip(ys).enumerate() {
+                    self.at(format!("arg{}", i + 1), |d| d.expr(x, y))?;
+                }
+                Ok(())
+            }
+            (Expr::Match(a, aps, aes), Expr::Match(b, bps, bes)) if aps.len() == bps.len() => {
+                self.at("scrutinee", |d| d.expr(a, b))?;
+                // Clauses are tried in order, so they must correspond in order
+                let clauses = aps.iter().zip(aes).zip(bps.iter().zip(bes));
+                for (i, ((ap, ae), (bp, be))) in clauses.enumerate() {
+                    self.at(format!("clause{}", i + 1), |d| {
+                        let mut binders = Vec::new();
+                        d.at("pattern", |d| d.pattern(ap, bp, &mut binders))?;
+                        d.scoped(&binders, |d| d.at("body", |d| d.expr(ae, be)))
+                    })?;
+                }
+                Ok(())
+            }
+            // Lambda ids only name the closure's code, so they never need to match
+            (Expr::Lam(_, xs, a), Expr::Lam(_, ys, b)) => {
+                self.params(xs, ys, |d| d.at("body", |d| d.expr(a, b)))
+            }
+            (Expr::Unknown, Expr::Unknown) => Ok(()),
+            _ => self.differ_exprs(a, b),
+        }
+    }
+
+    fn defn(&mut self, a: &Defn, b: &Defn) -> Diff {
+        self.var(a.0, b.0)
+            .or_else(|_| self.differ(name(&self.names[0], a.0), name(&self.names[1], b.0)))?;
+        self.params(&a.1, &b.1, |d| d.at("body", |d| d.expr(&a.2, &b.2)))
+    }
+
+    fn program(&mut self, a: &Program, b: &Program) -> Diff {
+        if a.defines.len() != b.defines.len() {
+            return self.at("defines", |d| {
+                d.differ(
+                    format!("{} definitions", a.defines.len()),
+                    format!("{} definitions", b.defines.len()),
+                )
+            });
+        }
+
+        // Pair each definition with the first unpaired one it is equivalent to.
+        // This is greedy, which is enough as long as no two definitions are
+        // equivalent to each other.
+        let mut unpaired: Vec<&Defn> = b.defines.iter().collect();
+        for (i, defn) in a.defines.iter().enumerate() {
+            let step = format!("define{}", i + 1);
+            let paired = unpaired.iter().position(|other| {
+                let mut attempt = self.clone();
+                attempt.at(step.as_str(), |d| d.defn(defn, other)).is_ok()
+            });
+            match paired {
+                Some(j) => {
+                    let other = unpaired.remove(j);
+                    self.at(step, |d| d.defn(defn, other))?;
+                }
+                // Report the difference against the definition in the same position
+                // if it is still unpaired, since that's most likely the intended one
+                None => {
+                    let other = unpaired
+                        .iter()
+                        .find(|other| std::ptr::eq(**other, &b.defines[i]))
+                        .unwrap_or(&unpaired[0]);
+                    self.at(step, |d| d.defn(defn, other))?;
+                }
+            }
+        }
+
+        self.at("expr", |d| d.expr(&a.expr, &b.expr))
+    }
+}
+
+/// The first difference between `a` and `b` up to renaming, if any
+pub fn diff_exprs(a: &Expr, b: &Expr) -> Option<Difference> {
+    let mut differ = Differ::default();
+    differ.expr(a, b).err()
+}
+
+/// The first difference between `a` and `b` up to renaming and reordering of
+/// definitions, if any
+pub fn diff_programs(a: &Program, b: &Program) -> Option<Difference> {
+    let mut differ = Differ {
+        names: [a.display_names(), b.display_names()],
+        ..Differ::default()
+    };
+    differ.program(a, b).err()
+}
+
+pub fn alpha_equivalent(a: &Program, b: &Program) -> bool {
+    diff_programs(a, b).is_none()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_program;
+
+    fn diff(a: &str, b: &str) -> Option<String> {
+        let (a, b) = (parse_program(a).unwrap(), parse_program(b).unwrap());
+        diff_programs(&a, &b).map(|d| d.to_string())
+    }
+
+    #[test]
+    fn renaming_is_consistent() {
+        assert_eq!(diff("(let ([x 1]) (+ x y))", "(let ([a 1]) (+ a b))"), None);
+        assert_eq!(
+            diff("(let ([x 1]) (+ x x))", "(let ([a 1]) (+ a b))"),
+            Some("at expr/body/arg2: `x` differs from `b`".to_owned())
+        );
+        // Shadowing: the inner binders correspond, not the outer ones
+        assert_eq!(
+            diff(
+                "(let ([x 1]) (let ([x 2]) x))",
+                "(let ([a 1]) (let ([b 2]) b))"
+            ),
+            None
+        );
+        assert!(
+            diff(
+                "(let ([x 1]) (let ([y 2]) x))",
+                "(let ([a 1]) (let ([b 2]) b))"
+            )
+            .is_some()
+        );
+        // Two free variables can't both become the same one
+        assert!(diff("(+ x y)", "(+ z z)").is_some());
+    }
+
+    #[test]
+    fn definitions_pair_in_any_order() {
+        let a = "(define (f n) (g n)) (define (g m) (add1 m)) (f 1)";
+        let b = "(define (h x) (add1 x)) (define (k y) (h y)) (k 1)";
+        assert_eq!(diff(a, b), None);
+        assert_eq!(
+            diff(a, "(define (h x) (sub1 x)) (define (k y) (h y)) (k 1)"),
+            Some("at define2/body: `(add1 m)` differs from `(sub1 x)`".to_owned())
+        );
+    }
+
+    #[test]
+    fn match_clauses_bind_pattern_variables() {
+        let a = "(match e [(cons x y) (+ x y)] [_ 0])";
+        assert_eq!(diff(a, "(match e [(cons p q) (+ p q)] [_ 0])"), None);
+        assert_eq!(
+            diff(a, "(match e [(cons p q) (+ q p)] [_ 0])"),
+            Some("at expr/clause1/body/arg1: `x` differs from `q`".to_owned())
+        );
+        assert!(diff(a, "(match e [_ 0] [(cons x y) (+ x y)])").is_some());
+    }
+}
@@ -0,0 +1,162 @@
+use std::collections::HashMap;
+
+use anyhow::{Context, Result, bail};
+use iced_x86::{BlockEncoder, BlockEncoderOptions, InstructionBlock};
+
+use crate::a86::{Address, Arg, Instruction, Memory};
+
+/// Labels are handed out as addresses in this range until the program is
+/// laid out, much like the course a86 library refers to labels by symbol
+const LABEL_BASE: Address = 1 << 63;
+/// Placeholder instruction pointers given to the block encoder so that it can
+/// resolve branches between instructions of the same block
+const PSEUDO_IP_BASE: Address = 1 << 62;
+
+/// Assembles a86 instructions into x86-64 machine code
+#[derive(Debug, Default)]
+pub struct Assembler {
+    instructions: Vec<Instruction>,
+
+    /// Label names, indexed by label id
+    labels: Vec<String>,
+    label_ids: HashMap<String, usize>,
+    /// The index of the instruction each label is bound to
+    bound: HashMap<usize, usize>,
+}
+
+/// The output of [`Assembler::assemble`]
+#[derive(Debug)]
+pub struct Assembly {
+    pub code: Vec<u8>,
+    /// The address of every instruction, in order
+    pub addresses: Vec<Address>,
+    /// The address of every bound label
+    pub labels: HashMap<String, Address>,
+}
+
+impl Assembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a placeholder address for the label `name`, usable as the
+    /// target of `Call`, jumps and `Lea` before the label is bound
+    pub fn label(&mut self, name: &str) -> Address {
+        let id = match self.label_ids.get(name) {
+            Some(&id) => id,
+            None => {
+                self.labels.push(name.to_owned());
+                self.label_ids
+                    .insert(name.to_owned(), self.labels.len() - 1);
+                self.labels.len() - 1
+            }
+        };
+        LABEL_BASE + id as Address
+    }
+
+    /// Binds the label `name` to the next instruction pushed
+    pub fn bind(&mut self, name: &str) -> Result<()> {
+        let id = (self.label(name) - LABEL_BASE) as usize;
+        if self.bound.insert(id, self.instructions.len()).is_some() {
+            bail!("label {} bound more than once", name);
+        }
+        Ok(())
+    }
+
+    pub fn push(&mut self, instruction: Instruction) {
+        self.instructions.push(instruction);
+    }
+
+    pub fn instructions(&self) -> &[Instruction] {
+        self.instructions.as_slice()
+    }
+
+    /// Lays out the instructions starting at `base`, resolving labels
+    pub fn assemble(&self, base: Address) -> Result<Assembly> {
+        let resolve = |address: Address| -> Result<Address> {
+            if address < LABEL_BASE {
+                return Ok(address);
+            }
+            let id = (address - LABEL_BASE) as usize;
+            let &index = self
+                .bound
+                .get(&id)
+                .with_context(|| format!("label {} is never bound", self.labels[id]))?;
+            if index == self.instructions.len() {
+                bail!(
+                    "label {} is not followed by an instruction",
+                    self.labels[id]
+                );
+            }
+            Ok(PSEUDO_IP_BASE + index as Address)
+        };
+
+        let mut block = Vec::with_capacity(self.instructions.len());
+        for (i, &instruction) in self.instructions.iter().enumerate() {
+            let mut encoded: iced_x86::Instruction = relocate(instruction, resolve)?
+                .try_into()
+                .with_context(|| format!("failed to encode instruction {i}"))?;
+            encoded.set_ip(PSEUDO_IP_BASE + i as Address);
+            block.push(encoded);
+        }
+
+        let result = BlockEncoder::encode(
+            64,
+            InstructionBlock::new(&block, base),
+            BlockEncoderOptions::RETURN_NEW_INSTRUCTION_OFFSETS,
+        )
+        .context("failed to lay out instructions")?;
+
+        let addresses: Vec<Address> = result
+            .new_instruction_offsets
+            .iter()
+            .map(|&offset| base + offset as Address)
+            .collect();
+        let end = base + result.code_buffer.len() as Address;
+        let labels = self
+            .bound
+            .iter()
+            .map(|(&id, &index)| {
+                let address = addresses.get(index).copied().unwrap_or(end);
+                (self.labels[id].clone(), address)
+            })
+            .collect();
+
+        Ok(Assembly {
+            code: result.code_buffer,
+            addresses,
+            labels,
+        })
+    }
+}
+
+/// Rewrites every address operand of `instruction` with `f`
+fn relocate(
+    instruction: Instruction,
+    f: impl Fn(Address) -> Result<Address>,
+) -> Result<Instruction> {
+    let arg = |arg: Arg| -> Result<Arg> {
+        Ok(match arg {
+            Arg::Address(address) => Arg::Address(f(address)?),
+            Arg::Memory(memory) => match memory.target() {
+                Some(address) => Arg::Memory(Memory::rel(f(address)?)),
+                None => arg,
+            },
+            arg => arg,
+        })
+    };
+
+    Ok(match instruction {
+        Instruction::Call(address) => Instruction::Call(f(address)?),
+        Instruction::Jmp(target) => Instruction::Jmp(arg(target)?),
+        Instruction::Jne(target) => Instruction::Jne(arg(target)?),
+        Instruction::Je(target) => Instruction::Je(arg(target)?),
+        Instruction::Jl(target) => Instruction::Jl(arg(target)?),
+        Instruction::Jg(target) => Instruction::Jg(arg(target)?),
+        Instruction::Jle(target) => Instruction::Jle(arg(target)?),
+        Instruction::Jge(target) => Instruction::Jge(arg(target)?),
+        Instruction::Lea(dst, src) => Instruction::Lea(dst, arg(src)?),
+        Instruction::Mov(dst, src) => Instruction::Mov(arg(dst)?, arg(src)?),
+        instruction => instruction,
+    })
+}
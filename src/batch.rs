@@ -0,0 +1,280 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+};
+
+use anyhow::{Context, Result};
+use serde_json::{Value, json};
+
+use crate::{
+    DecompileOptions, Diagnostic,
+    a86::{Address, Program as A86Program},
+    decompile,
+    loot::Program as LootProgram,
+};
+
+/*
+  Batch mode: decompiling every `.run` under a directory, as when grading a
+  class's worth of submissions. Each program's source is written next to it
+  as `<name>.decompiled.rkt`, and the outcomes are summarized in a table.
+*/
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Status {
+    Decompiled,
+    /// Decompiled, but with parts the decompiler couldn't make sense of
+    Partial {
+        unknowns: usize,
+    },
+    /// Not decompiled, with where the decompiler stopped if it's known
+    Failed {
+        address: Option<Address>,
+        reason: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Outcome {
+    pub program: PathBuf,
+    pub status: Status,
+}
+
+/// Every `.run` file under `dir`, in order
+pub fn find_programs(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut programs = Vec::new();
+    let entries = fs::read_dir(dir).with_context(|| format!("failed to read {}", dir.display()))?;
+    for entry in entries {
+        let entry = entry?;
+        let path = entry.path();
+        // Symbolic links aren't followed, so that a link to a parent directory
+        // doesn't make the search go round forever
+        if entry.file_type()?.is_dir() {
+            programs.extend(find_programs(&path)?);
+        } else if path.extension().is_some_and(|e| e == "run") {
+            programs.push(path);
+        }
+    }
+    programs.sort();
+    Ok(programs)
+}
+
+/// Where the source decompiled from `program` is written
+pub fn output_path(program: &Path) -> PathBuf {
+    program.with_extension("decompiled.rkt")
+}
+
+/// Decompiles `program`, writing its source as `render` lays it out
+fn decompile_one(
+    program: &Path,
+    options: &DecompileOptions,
+    render: &impl Fn(&LootProgram) -> String,
+) -> Result<Status> {
+    let binary = A86Program::from_file(program)?;
+    let loot_program = decompile(&binary, options)?.program;
+    let path = output_path(program);
+    fs::write(&path, render(&loot_program) + "\n")
+        .with_context(|| format!("failed to write {}", path.display()))?;
+    Ok(match loot_program.unknowns() {
+        0 => Status::Decompiled,
+        unknowns => Status::Partial { unknowns },
+    })
+}
+
+/// The status of a program that couldn't be decompiled because of `error`
+fn failed(error: anyhow::Error) -> Status {
+    match error.downcast_ref::<Diagnostic>() {
+        Some(diagnostic) => Status::Failed {
+            address: diagnostic.address,
+            reason: diagnostic.message.clone(),
+        },
+        None => Status::Failed {
+            address: None,
+            reason: format!("{:#}", error),
+        },
+    }
+}
+
+/// Decompiles `programs` with `options` on `jobs` threads, writing each one's
+/// source as `render` lays it out. The outcomes are in the same order as
+/// `programs`.
+pub fn run(
+    programs: &[PathBuf],
+    jobs: usize,
+    options: &DecompileOptions,
+    render: impl Fn(&LootProgram) -> String + Sync,
+) -> Vec<Outcome> {
+    let next = AtomicUsize::new(0);
+    let mut statuses: Vec<(usize, Status)> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..jobs.max(1))
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut statuses = Vec::new();
+                    loop {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(program) = programs.get(i) else {
+                            break statuses;
+                        };
+                        let status =
+                            decompile_one(program, options, &render).unwrap_or_else(failed);
+                        statuses.push((i, status));
+                    }
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().unwrap())
+            .collect()
+    });
+    statuses.sort_by_key(|&(i, _)| i);
+    statuses
+        .into_iter()
+        .map(|(i, status)| Outcome {
+            program: programs[i].clone(),
+            status,
+        })
+        .collect()
+}
+
+/// The outcomes as a table, with a line of totals
+pub fn report(outcomes: &[Outcome]) -> String {
+    let width = outcomes
+        .iter()
+        .map(|o| o.program.display().to_string().len())
+        .chain(["PROGRAM".len()])
+        .max()
+        .unwrap();
+
+    let mut out = format!("{:<width$}  {:<7}  DETAILS\n", "PROGRAM", "STATUS");
+    let (mut decompiled, mut partial, mut failed) = (0, 0, 0);
+    for outcome in outcomes {
+        let (status, details) = match &outcome.status {
+            Status::Decompiled => {
+                decompiled += 1;
+                ("ok", String::new())
+            }
+            Status::Partial { unknowns } => {
+                partial += 1;
+                ("partial", format!("{} unknown expression(s)", unknowns))
+            }
+            Status::Failed { address, reason } => {
+                failed += 1;
+                let details = match address {
+                    Some(address) => format!("{:#x}: {}", address, reason),
+                    None => reason.clone(),
+                };
+                ("failed", details)
+            }
+        };
+        let line = format!(
+            "{:<width$}  {:<7}  {}",
+            outcome.program.display(),
+            status,
+            details
+        );
+        out += line.trim_end();
+        out.push('\n');
+    }
+    out += &format!(
+        "{} program(s): {} ok, {} partial, {} failed\n",
+        outcomes.len(),
+        decompiled,
+        partial,
+        failed
+    );
+    out
+}
+
+/// The outcomes as JSON, one object per program
+pub fn to_json(outcomes: &[Outcome]) -> Value {
+    let outcomes: Vec<Value> = outcomes
+        .iter()
+        .map(|outcome| {
+            let program = outcome.program.display().to_string();
+            match &outcome.status {
+                Status::Decompiled => json!({ "program": program, "status": "ok" }),
+                Status::Partial { unknowns } => {
+                    json!({ "program": program, "status": "partial", "unknowns": unknowns })
+                }
+                Status::Failed { address, reason } => json!({
+                    "program": program,
+                    "status": "failed",
+                    "address": address,
+                    "reason": reason,
+                }),
+            }
+        })
+        .collect();
+    json!(outcomes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        a86::{Arg::*, Instruction::*, Register::*},
+        elf_writer::{course_program, write_elf},
+    };
+
+    #[test]
+    fn reports_every_outcome() {
+        let dir = std::env::temp_dir().join(format!(
+            "compiler-deconstruction-{}-batch",
+            std::process::id()
+        ));
+        fs::create_dir_all(dir.join("nested")).unwrap();
+        let add1 = concat!(env!("CARGO_MANIFEST_DIR"), "/test-programs/add1.run");
+        fs::copy(add1, dir.join("add1.run")).unwrap();
+        fs::write(dir.join("nested/garbage.run"), b"not an ELF file").unwrap();
+        let partial = course_program(|asm| asm.push(Xor(Register(Rax), Register(Rax))));
+        fs::write(dir.join("partial.run"), write_elf(partial).unwrap()).unwrap();
+        // An integer type check with nothing before it to check
+        let unsupported = course_program(|asm| {
+            let err = asm.label("err");
+            asm.push(Mov(Register(R9), Register(Rax)));
+            asm.push(And(Register(R9), Literal(0xf)));
+            asm.push(Cmp(Register(R9), Literal(0)));
+            asm.push(Jne(Address(err)));
+            asm.push(Add(Register(Rax), Literal(0x10)));
+        });
+        fs::write(dir.join("unsupported.run"), write_elf(unsupported).unwrap()).unwrap();
+        // A value with no type's tag
+        let untagged = course_program(|asm| asm.push(Mov(Register(Rax), Literal(0x7))));
+        fs::write(dir.join("untagged.run"), write_elf(untagged).unwrap()).unwrap();
+        // A link back up, which isn't followed
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(&dir, dir.join("nested/parent")).unwrap();
+
+        let programs = find_programs(&dir).unwrap();
+        let outcomes = run(&programs, 2, &DecompileOptions::default(), |program| {
+            program.to_string()
+        });
+        let written = fs::read_to_string(dir.join("add1.decompiled.rkt"));
+        let statuses: Vec<_> = outcomes.iter().map(|o| o.status.clone()).collect();
+        let table = report(&outcomes);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(written.unwrap().starts_with("#lang racket\n(begin (add1"));
+        assert!(matches!(statuses[0], Status::Decompiled));
+        assert!(matches!(
+            &statuses[1],
+            Status::Failed { address: None, reason } if reason.contains("ELF")
+        ));
+        assert_eq!(statuses[2], Status::Partial { unknowns: 1 });
+        assert!(matches!(
+            &statuses[3],
+            Status::Failed { address: Some(_), reason } if reason == "add1 of nothing"
+        ));
+        assert!(matches!(
+            &statuses[4],
+            Status::Failed { address: Some(_), reason } if reason == "unknown constant"
+        ));
+        assert!(
+            table.ends_with("5 program(s): 1 ok, 1 partial, 3 failed\n"),
+            "{}",
+            table
+        );
+    }
+}
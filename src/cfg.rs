@@ -0,0 +1,134 @@
+use std::{collections::BTreeSet, ops::Range};
+
+use crate::a86::{Arg, Instruction, Program as A86Program};
+
+/*
+  The control-flow graph of a program's instructions: basic blocks are maximal
+  runs of instructions that are only entered at the top and only left at the
+  bottom. Calls are assumed to return, so they don't end blocks.
+*/
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BasicBlock {
+    /// Indices of the block's instructions
+    pub instructions: Range<usize>,
+    /// Indices of the blocks control can go to next, fall-through first
+    pub successors: Vec<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cfg {
+    pub blocks: Vec<BasicBlock>,
+}
+
+/// Where control can go after `instruction`: the index of the jump target (if
+/// it's a known instruction) and whether it can fall through
+fn successors(program: &A86Program, instruction: &Instruction) -> (Option<usize>, bool) {
+    let target = |arg: &Arg| match arg {
+        Arg::Address(address) => program.address_to_index(*address),
+        _ => None,
+    };
+    match instruction {
+        Instruction::Jmp(arg) => (target(arg), false),
+        Instruction::Je(arg)
+        | Instruction::Jne(arg)
+        | Instruction::Jl(arg)
+        | Instruction::Jle(arg)
+        | Instruction::Jg(arg)
+        | Instruction::Jge(arg) => (target(arg), true),
+        Instruction::Ret => (None, false),
+        _ => (None, true),
+    }
+}
+
+fn ends_block(instruction: &Instruction) -> bool {
+    matches!(
+        instruction,
+        Instruction::Jmp(_)
+            | Instruction::Je(_)
+            | Instruction::Jne(_)
+            | Instruction::Jl(_)
+            | Instruction::Jle(_)
+            | Instruction::Jg(_)
+            | Instruction::Jge(_)
+            | Instruction::Ret
+    )
+}
+
+impl Cfg {
+    pub fn build(program: &A86Program) -> Cfg {
+        let instructions = program.instructions();
+
+        // Every function starts a block, as does whatever comes first
+        let mut leaders = BTreeSet::from([0]);
+        leaders.extend(
+            program
+                .functions()
+                .iter()
+                .filter_map(|&address| program.address_to_index(address)),
+        );
+        for (i, instruction) in instructions.iter().enumerate() {
+            if ends_block(instruction) {
+                leaders.insert(i + 1);
+                leaders.extend(successors(program, instruction).0);
+            }
+        }
+        let leaders: Vec<usize> = leaders
+            .into_iter()
+            .filter(|&i| i < instructions.len())
+            .collect();
+
+        let block_of = |index: usize| leaders.binary_search(&index).ok();
+        let blocks = leaders
+            .iter()
+            .enumerate()
+            .map(|(b, &start)| {
+                let end = leaders.get(b + 1).copied().unwrap_or(instructions.len());
+                let (target, falls_through) = successors(program, &instructions[end - 1]);
+                let mut successors = Vec::new();
+                if falls_through && end < instructions.len() {
+                    successors.push(b + 1);
+                }
+                successors.extend(target.and_then(block_of));
+                BasicBlock {
+                    instructions: start..end,
+                    successors,
+                }
+            })
+            .collect();
+
+        Cfg { blocks }
+    }
+
+    /// The index of the block containing the instruction at `index`
+    pub fn block_containing(&self, index: usize) -> Option<usize> {
+        self.blocks
+            .iter()
+            .position(|block| block.instructions.contains(&index))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn if_splits_into_diamond() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/test-programs/if.run");
+        let program = A86Program::from_elf_file(path).unwrap();
+        let cfg = Cfg::build(&program);
+
+        // entry and test, then, else, join (epilogue), err
+        let successors: Vec<_> = cfg.blocks.iter().map(|b| b.successors.clone()).collect();
+        assert_eq!(
+            successors,
+            vec![vec![1, 2], vec![3], vec![3], vec![], vec![]]
+        );
+        assert_eq!(cfg.block_containing(0), Some(0));
+        assert!(
+            cfg.blocks
+                .windows(2)
+                .all(|pair| { pair[0].instructions.end == pair[1].instructions.start })
+        );
+    }
+}
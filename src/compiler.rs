@@ -0,0 +1,321 @@
+use anyhow::{Result, bail};
+
+use crate::{
+    a86::{Arg, Instruction, Register},
+    assembler::Assembler,
+    loot::{Datum, Expr, Operation, Program as LootProgram},
+};
+
+/*
+  A compiler for the subset of Loot the decompiler understands, producing the
+  same instruction sequences as the course compiler (compile.rkt) so that
+  fixtures built with it look like real student binaries.
+*/
+
+const TYPE_INT: u64 = 0b0000;
+const MASK_INT: u64 = 0b1111;
+const VAL_TRUE: u64 = 0b011000;
+const VAL_FALSE: u64 = 0b111000;
+const VAL_EOF: u64 = 0b1011000;
+const VAL_VOID: u64 = 0b1111000;
+const VAL_EMPTY: u64 = 0b10011000;
+
+/// The bit representation of a literal, as in `value->bits`
+pub fn value_to_bits(datum: &Datum) -> Result<u64> {
+    Ok(match datum {
+        Datum::Integer(i) => (*i as u64) << 4,
+        Datum::Boolean(true) => VAL_TRUE,
+        Datum::Boolean(false) => VAL_FALSE,
+        Datum::Character(c) => ((*c as u64) << 5) | 0b01000,
+        Datum::Eof => VAL_EOF,
+        Datum::Empty => VAL_EMPTY,
+        Datum::String(_) => bail!("string literals are heap-allocated and not supported"),
+    })
+}
+
+struct Compiler {
+    asm: Assembler,
+    /// Counter for `gensym`-style label names
+    next_label: usize,
+}
+
+impl Compiler {
+    fn gensym(&mut self, prefix: &str) -> String {
+        self.next_label += 1;
+        format!("{}{}", prefix, self.next_label)
+    }
+
+    fn emit(&mut self, instruction: Instruction) {
+        self.asm.push(instruction);
+    }
+
+    fn pad_stack(&mut self) {
+        use Register::*;
+        self.emit(Instruction::Mov(Arg::Register(R15), Arg::Register(Rsp)));
+        self.emit(Instruction::And(Arg::Register(R15), Arg::Literal(0b1000)));
+        self.emit(Instruction::Sub(Arg::Register(Rsp), Arg::Register(R15)));
+    }
+
+    fn unpad_stack(&mut self) {
+        use Register::*;
+        self.emit(Instruction::Add(Arg::Register(Rsp), Arg::Register(R15)));
+    }
+
+    fn assert_integer(&mut self, register: Register) {
+        use Register::*;
+        let err = self.asm.label("err");
+        self.emit(Instruction::Mov(Arg::Register(R9), Arg::Register(register)));
+        self.emit(Instruction::And(
+            Arg::Register(R9),
+            Arg::Literal(MASK_INT as i64),
+        ));
+        self.emit(Instruction::Cmp(
+            Arg::Register(R9),
+            Arg::Literal(TYPE_INT as i64),
+        ));
+        self.emit(Instruction::Jne(Arg::Address(err)));
+    }
+
+    fn call_runtime(&mut self, function: &str) {
+        let function = self.asm.label(function);
+        self.pad_stack();
+        self.emit(Instruction::Call(function));
+        self.unpad_stack();
+    }
+
+    fn compile_expr(&mut self, expr: &Expr) -> Result<()> {
+        use Register::*;
+        match expr {
+            Expr::Literal(datum) => {
+                let bits = value_to_bits(datum)?;
+                self.emit(Instruction::Mov(
+                    Arg::Register(Rax),
+                    Arg::Literal(bits as i64),
+                ));
+            }
+            Expr::Op(Operation::Void) => {
+                self.emit(Instruction::Mov(
+                    Arg::Register(Rax),
+                    Arg::Literal(VAL_VOID as i64),
+                ));
+            }
+            Expr::Op(Operation::ReadByte) => self.call_runtime("read_byte"),
+            Expr::Op(Operation::PeekByte) => self.call_runtime("peek_byte"),
+            Expr::Op(Operation::Add1(e)) => {
+                self.compile_expr(e)?;
+                self.assert_integer(Rax);
+                self.emit(Instruction::Add(Arg::Register(Rax), Arg::Literal(1 << 4)));
+            }
+            Expr::Op(Operation::Plus(e1, e2)) => {
+                self.compile_expr(e1)?;
+                self.emit(Instruction::Push(Arg::Register(Rax)));
+                self.compile_expr(e2)?;
+                self.emit(Instruction::Pop(Arg::Register(R8)));
+                self.assert_integer(R8);
+                self.assert_integer(Rax);
+                self.emit(Instruction::Add(Arg::Register(Rax), Arg::Register(R8)));
+            }
+            Expr::If(e1, e2, e3) => {
+                let (l1, l2) = (self.gensym("if"), self.gensym("if"));
+                let (if_false, if_end) = (self.asm.label(&l1), self.asm.label(&l2));
+                self.compile_expr(e1)?;
+                self.emit(Instruction::Cmp(
+                    Arg::Register(Rax),
+                    Arg::Literal(VAL_FALSE as i64),
+                ));
+                self.emit(Instruction::Je(Arg::Address(if_false)));
+                self.compile_expr(e2)?;
+                self.emit(Instruction::Jmp(Arg::Address(if_end)));
+                self.asm.bind(&l1)?;
+                self.compile_expr(e3)?;
+                self.asm.bind(&l2)?;
+            }
+            Expr::Begin(es) => {
+                for e in es {
+                    self.compile_expr(e)?;
+                }
+            }
+            _ => bail!("compiling {:?} is not supported", expr),
+        }
+        Ok(())
+    }
+}
+
+/// Compiles `program` into the layout the course compiler produces: the
+/// `entry` label, the main expression, the epilogue, and the `err` handler
+pub fn compile(program: &LootProgram) -> Result<Assembler> {
+    use Register::*;
+
+    if !program.defines.is_empty() {
+        bail!("compiling definitions is not supported");
+    }
+
+    let mut c = Compiler {
+        asm: Assembler::new(),
+        next_label: 0,
+    };
+
+    c.asm.bind("entry")?;
+    c.emit(Instruction::Push(Arg::Register(Rbx)));
+    c.emit(Instruction::Push(Arg::Register(R15)));
+    c.emit(Instruction::Mov(Arg::Register(Rbx), Arg::Register(Rdi)));
+    // Allocating the (zero) function closures
+    c.emit(Instruction::Add(Arg::Register(Rbx), Arg::Literal(0)));
+    c.compile_expr(&program.expr)?;
+    // Popping the (zero) function definitions
+    c.emit(Instruction::Add(Arg::Register(Rsp), Arg::Literal(0)));
+    c.emit(Instruction::Pop(Arg::Register(R15)));
+    c.emit(Instruction::Pop(Arg::Register(Rbx)));
+    c.emit(Instruction::Ret);
+
+    // raise_error does not return, so there is no unpad-stack here
+    c.asm.bind("err")?;
+    c.pad_stack();
+    let raise_error = c.asm.label("raise_error");
+    c.emit(Instruction::Call(raise_error));
+
+    Ok(c.asm)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::{
+        a86::Program as A86Program, alpha::diff_programs, decompiler::parse, elf_writer::write_elf,
+    };
+
+    fn roundtrip(program: &LootProgram) -> Result<LootProgram> {
+        static FIXTURES: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "compiler-deconstruction-{}-roundtrip-{}.run",
+            std::process::id(),
+            FIXTURES.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::write(&path, write_elf(compile(program)?)?)?;
+        let binary = A86Program::from_elf_file(&path);
+        fs::remove_file(&path)?;
+        parse(&binary?)
+    }
+
+    fn leaf(integer: bool) -> BoxedStrategy<Expr> {
+        let int = prop_oneof![
+            // Integers that fit in signed 32-bit immediates, ones that only
+            // fit unsigned (moved into `eax`), and ones that don't fit
+            2 => (-(1i64 << 27)..1 << 27).prop_map(|i| Expr::Literal(Datum::Integer(i))),
+            1 => (1i64 << 27..1 << 28).prop_map(|i| Expr::Literal(Datum::Integer(i))),
+            1 => (i64::MIN >> 4..=i64::MAX >> 4).prop_map(|i| Expr::Literal(Datum::Integer(i))),
+            1 => Just(Expr::Op(Operation::ReadByte)),
+            1 => Just(Expr::Op(Operation::PeekByte)),
+        ];
+        if integer {
+            return int.boxed();
+        }
+        prop_oneof![
+            int,
+            any::<bool>().prop_map(|b| Expr::Literal(Datum::Boolean(b))),
+            any::<char>().prop_map(|c| Expr::Literal(Datum::Character(c))),
+            Just(Expr::Op(Operation::Void)),
+        ]
+        .boxed()
+    }
+
+    /// Whether the first operation `expr` evaluates other than in a branch of
+    /// an `if` is a `+`
+    fn starts_with_sum(expr: &Expr) -> bool {
+        match expr {
+            Expr::Op(Operation::Plus(..)) => true,
+            Expr::Op(Operation::Add1(e)) | Expr::If(e, _, _) => starts_with_sum(e),
+            Expr::Begin(es) => starts_with_sum(&es[0]),
+            _ => false,
+        }
+    }
+
+    /// Whether a normalized expression is in the form the decompiler emits.
+    /// Code doesn't show where the effects before an operation were in the
+    /// source, so `(begin a (add1 b))` and `(add1 (begin a b))` look the same;
+    /// the decompiler puts them around the operation, unless it starts with a
+    /// `+`, in which case they're in its first operand: `(+ (begin a b) c)`.
+    fn is_normal(expr: &Expr) -> bool {
+        let operands_are_normal = expr.children().into_iter().all(is_normal);
+        match expr {
+            Expr::Op(Operation::Add1(e)) | Expr::Op(Operation::Plus(_, e)) | Expr::If(e, _, _)
+                if matches!(**e, Expr::Begin(_)) =>
+            {
+                false
+            }
+            Expr::Begin(es) if es[1..].iter().any(starts_with_sum) => false,
+            _ => operands_are_normal,
+        }
+    }
+
+    /// Well-typed expressions: `integer` ones can be used as operands of
+    /// `add1` and `+`, others only in effect or test position. They're
+    /// `begin`s only if `begins`, which operands aren't except the first of a
+    /// `+`, as in the decompiler's output.
+    fn expr(depth: u32, integer: bool, begins: bool) -> BoxedStrategy<Expr> {
+        if depth == 0 {
+            return leaf(integer);
+        }
+        let e = |integer, begins| expr(depth - 1, integer, begins);
+        let mut strategies = vec![
+            (2, leaf(integer)),
+            (
+                1,
+                e(true, false)
+                    .prop_map(|e| Expr::Op(Operation::Add1(Box::new(e))))
+                    .boxed(),
+            ),
+            (
+                1,
+                (e(true, true), e(true, false))
+                    .prop_map(|(e1, e2)| Expr::Op(Operation::Plus(Box::new(e1), Box::new(e2))))
+                    .boxed(),
+            ),
+            (
+                1,
+                (e(false, false), e(integer, true), e(integer, true))
+                    .prop_map(|(e1, e2, e3)| Expr::If(Box::new(e1), Box::new(e2), Box::new(e3)))
+                    .boxed(),
+            ),
+        ];
+        if begins {
+            let begin = (
+                prop::collection::vec(e(false, true), 1..3),
+                e(integer, true),
+            )
+                .prop_map(|(mut es, e)| {
+                    es.push(e);
+                    Expr::Begin(es)
+                });
+            strategies.push((1, begin.boxed()));
+        }
+        prop::strategy::Union::new_weighted(strategies).boxed()
+    }
+
+    /// Expressions in the form the decompiler emits, so that decompiling one
+    /// compiled gives it back
+    fn normal_expr() -> impl Strategy<Value = Expr> {
+        expr(3, false, true)
+            .prop_map(|mut e| {
+                e.normalize();
+                e
+            })
+            .prop_filter("not in the decompiler's form", is_normal)
+    }
+
+    proptest! {
+        #[test]
+        fn decompile_inverts_compile(e in normal_expr()) {
+            let program = LootProgram { defines: Vec::new(), expr: Box::new(e), names: Default::default() };
+            let decompiled = roundtrip(&program).unwrap();
+            prop_assert_eq!(diff_programs(&program, &decompiled), None);
+        }
+    }
+}
@@ -1,14 +1,14 @@
-use std::collections::VecDeque;
+use std::ops::Range;
 
-use anyhow::Result;
 use anyhow::bail;
+use anyhow::{Context, Result};
 
 use crate::{
     a86::{Address, Arg, Instruction, Program as A86Program, Register},
-    loot::{Datum, Defn, Expr, Program as LootProgram, Operation},
+    loot::{Datum, Defn, Expr, Names, Operation, Program as LootProgram},
 };
 
-pub fn parse_const(lit: u64) -> Option<Expr> {
+pub fn parse_const(lit: i64) -> Option<Expr> {
     /*
       Bit layout of values
 
@@ -29,34 +29,168 @@ pub fn parse_const(lit: u64) -> Option<Expr> {
         0b011000 => Some(Expr::Literal(Datum::Boolean(true))),
         0b111000 => Some(Expr::Literal(Datum::Boolean(false))),
         0b1111000 => Some(Expr::Op(Operation::Void)),
-        lit if lit & 0b11111 == 0b01000 => Some(Expr::Literal(Datum::Character(char::from_u32(
-            (lit >> 5).try_into().unwrap(),
-        )?))),
-        lit if lit & 0b1111 == 0 => Some(Expr::Literal(Datum::Integer((lit >> 4) as i64))),
+        0b1011000 => Some(Expr::Literal(Datum::Eof)),
+        0b10011000 => Some(Expr::Literal(Datum::Empty)),
+        lit if lit & 0b11111 == 0b01000 => Some(Expr::Literal(Datum::Character(
+            u32::try_from(lit >> 5).ok().and_then(char::from_u32)?,
+        ))),
+        lit if lit & 0b1111 == 0 => Some(Expr::Literal(Datum::Integer(lit >> 4))),
         _ => None,
     }
 }
 
+/// The instructions an expression was decompiled from, and the origins of its
+/// subexpressions (in the order of `Expr::children`)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Origin {
+    /// Indices of the instructions, from the first instruction of the first
+    /// subexpression to the last instruction of the expression itself
+    pub instructions: Range<usize>,
+    /// Indices of the instructions matched for the expression itself, rather
+    /// than for its subexpressions
+    pub own: Vec<Range<usize>>,
+    pub children: Vec<Origin>,
+}
+
+impl Origin {
+    fn leaf(instructions: Range<usize>) -> Self {
+        Self {
+            own: vec![instructions.clone()],
+            instructions,
+            children: Vec::new(),
+        }
+    }
+
+    /// An expression made of `children` and its own instructions `own`, the
+    /// last of which end the expression
+    fn new(own: Vec<Range<usize>>, children: Vec<Origin>) -> Self {
+        let start = children
+            .iter()
+            .map(|child| child.instructions.start)
+            .chain(own.iter().map(|range| range.start))
+            .min()
+            .unwrap();
+        let end = own.last().unwrap().end;
+        Self {
+            instructions: start..end,
+            own,
+            children,
+        }
+    }
+}
+
+/// The decompiler didn't recognize the code at `address`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Unsupported {
+    pub address: Address,
+    pub reason: String,
+}
+
+impl std::fmt::Display for Unsupported {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} at {:#x}", self.reason, self.address)
+    }
+}
+
+impl std::error::Error for Unsupported {}
+
+/// An expression with its origin
+type Node = (Expr, Origin);
+
+/// The expressions evaluated in order, as a single expression. This builds
+/// `begin`s in the form `Expr::normalize` would leave them.
+fn sequence(nodes: Vec<Node>, end: usize) -> Node {
+    let start = nodes
+        .first()
+        .map_or(end, |(_, origin)| origin.instructions.start);
+    let mut body = Vec::new();
+    for (expr, origin) in nodes {
+        match expr {
+            Expr::Begin(es) => body.extend(es.into_iter().zip(origin.children)),
+            e => body.push((e, origin)),
+        }
+    }
+    // Literals evaluated for effect are dropped, and their instructions
+    // become the sequence's own
+    let last = body.pop();
+    let mut dropped = Vec::new();
+    body.retain(|(e, origin)| {
+        let pure = e.is_pure_literal();
+        if pure {
+            dropped.push(origin.instructions.clone());
+        }
+        !pure
+    });
+    body.extend(last);
+    match body.len() {
+        0 => (
+            Expr::Unknown,
+            Origin {
+                instructions: start..end,
+                own: dropped,
+                children: Vec::new(),
+            },
+        ),
+        1 => {
+            let (expr, mut origin) = body.pop().unwrap();
+            if !dropped.is_empty() {
+                origin.instructions.start = start;
+                dropped.append(&mut origin.own);
+                origin.own = dropped;
+            }
+            (expr, origin)
+        }
+        _ => {
+            let (exprs, children) = body.into_iter().unzip();
+            (
+                Expr::Begin(exprs),
+                Origin {
+                    instructions: start..end,
+                    own: dropped,
+                    children,
+                },
+            )
+        }
+    }
+}
+
 pub fn parse_expr(
     program: &A86Program,
     position: usize,
     stop: Option<usize>,
-    stack: &mut Vec<Expr>,
-) -> Result<(Expr, usize)> {
-    let mut expr_list = Vec::new();
+    stack: &mut Vec<(Node, usize)>,
+) -> Result<(Expr, Origin, usize)> {
+    let mut expr_list: Vec<Node> = Vec::new();
     let mut pos = position;
-    let err_label: Address = program.symbol_to_address("err").unwrap();
+    let err_label = program.symbol_to_address("err");
+    let unsupported = |index: usize, reason: &str| Unsupported {
+        address: program.index_to_address(index).unwrap_or_default(),
+        reason: reason.to_owned(),
+    };
 
     while match stop {
         Some(stop) => pos < stop,
         None => true,
     } {
         // peek on the next instructions
-        let (expr, new_pos) = match program.instructions()[pos..] {
+        let (expr, origin, new_pos) = match program.instructions()[pos..] {
             [
-                Instruction::Mov(Arg::Register(Register::Eax | Register::Rax), Arg::Literal(lit)),
+                Instruction::Mov(
+                    Arg::Register(r @ (Register::Eax | Register::Rax)),
+                    Arg::Literal(lit),
+                ),
                 ..,
-            ] => (parse_const(lit).unwrap(), pos + 1),
+            ] => (
+                // Writing `eax` zero-extends the (signed) immediate into `rax`
+                parse_const(if r.zero_extends() {
+                    lit as u32 as i64
+                } else {
+                    lit
+                })
+                .ok_or_else(|| unsupported(pos, "unknown constant"))?,
+                Origin::leaf(pos..pos + 1),
+                pos + 1,
+            ),
             [
                 // pad-stack + call + unpad-stack
                 Instruction::Mov(Arg::Register(Register::R15), Arg::Register(Register::Rsp)),
@@ -66,12 +200,13 @@ pub fn parse_expr(
                 Instruction::Add(Arg::Register(Register::Rsp), Arg::Register(Register::R15)),
                 ..,
             ] => {
-                if addr == program.symbol_to_address("read_byte").unwrap() {
-                    (Expr::Op(Operation::ReadByte), pos + 5)
-                } else if addr == program.symbol_to_address("peek_byte").unwrap() {
-                    (Expr::Op(Operation::PeekByte), pos + 5)
+                let origin = Origin::leaf(pos..pos + 5);
+                if Some(addr) == program.symbol_to_address("read_byte") {
+                    (Expr::Op(Operation::ReadByte), origin, pos + 5)
+                } else if Some(addr) == program.symbol_to_address("peek_byte") {
+                    (Expr::Op(Operation::PeekByte), origin, pos + 5)
                 } else {
-                    unimplemented!()
+                    bail!(unsupported(pos + 3, "call to an unknown function"))
                 }
             }
             [
@@ -82,8 +217,8 @@ pub fn parse_expr(
                 Instruction::Jne(Arg::Address(lab)),
                 ..,
             ] => {
-                if lab != err_label {
-                    bail!("expected jump to err label")
+                if Some(lab) != err_label {
+                    bail!(unsupported(pos + 3, "expected jump to err label"))
                 }
                 match program.instructions()[pos + 4..] {
                     [
@@ -91,15 +226,17 @@ pub fn parse_expr(
                         ..,
                     ] => {
                         // looks like an Add1
-                        let v = expr_list.pop();
+                        let (v, origin) = expr_list
+                            .pop()
+                            .ok_or_else(|| unsupported(pos + 4, "add1 of nothing"))?;
+                        let own = pos..pos + 5;
                         (
-                            Expr::Op(Operation::Add1(
-                                Box::new(v.unwrap()),
-                            )),
+                            Expr::Op(Operation::Add1(Box::new(v))),
+                            Origin::new(vec![own], vec![origin]),
                             pos + 5,
                         )
                     }
-                    _ => unimplemented!(),
+                    _ => bail!(unsupported(pos + 4, "unknown operation on an integer")),
                 }
             }
             [
@@ -107,13 +244,9 @@ pub fn parse_expr(
                 ..,
             ] => {
                 // current expression got pushed, start parsing a new one
-                let mut expr = expr_list.pop().unwrap();
-                while expr_list.len() > 0 {
-                    expr = Expr::Begin(Box::new(expr_list.pop().unwrap()), Box::new(expr));
-                }
-                stack.push(expr);
+                stack.push((sequence(std::mem::take(&mut expr_list), pos), pos));
 
-                parse_expr(program, pos + 1, None, stack)?
+                parse_expr(program, pos + 1, stop, stack)?
             }
             [
                 // pop + type check r8 and rax for int
@@ -128,8 +261,8 @@ pub fn parse_expr(
                 Instruction::Jne(Arg::Address(lab2)),
                 ..,
             ] => {
-                if lab1 != err_label || lab2 != err_label {
-                    bail!("expected jump to err label")
+                if Some(lab1) != err_label || Some(lab2) != err_label {
+                    bail!(unsupported(pos + 4, "expected jump to err label"))
                 }
                 match program.instructions()[pos + 9..] {
                     [
@@ -137,17 +270,22 @@ pub fn parse_expr(
                         ..,
                     ] => {
                         // looks like a Plus
-                        let arg1 = stack.pop();
-                        let arg2 = expr_list.pop();
+                        let ((arg1, origin1), push) = stack
+                            .pop()
+                            .ok_or_else(|| unsupported(pos, "pop without a push"))?;
+                        let (arg2, origin2) = expr_list
+                            .pop()
+                            .ok_or_else(|| unsupported(pos + 9, "+ of nothing"))?;
                         (
-                            Expr::Op(Operation::Plus(
-                                Box::new(arg1.unwrap()),
-                                Box::new(arg2.unwrap()),
-                            )),
+                            Expr::Op(Operation::Plus(Box::new(arg1), Box::new(arg2))),
+                            Origin::new(
+                                vec![push..push + 1, pos..pos + 10],
+                                vec![origin1, origin2],
+                            ),
                             pos + 10,
                         )
                     }
-                    _ => unimplemented!(),
+                    _ => bail!(unsupported(pos + 9, "unknown operation on two integers")),
                 }
             }
             [
@@ -155,34 +293,50 @@ pub fn parse_expr(
                 Instruction::Je(Arg::Address(if_false)),
                 ..,
             ] => {
-                let jmp_loc = program.address_to_index(if_false).unwrap() - 1;
+                let false_start = program
+                    .address_to_index(if_false)
+                    .filter(|&i| i > pos + 2)
+                    .ok_or_else(|| unsupported(pos + 1, "expected a forward jump in an if"))?;
+                let jmp_loc = false_start - 1;
                 // We are in an if statement.
-                let expr_if_true = parse_expr(program, pos + 2, Some(jmp_loc), stack)?.0;
+                let (expr_if_true, origin_if_true, _) =
+                    parse_expr(program, pos + 2, Some(jmp_loc), stack)?;
 
                 let if_end = match program.instructions()[jmp_loc] {
-                    Instruction::Jmp(Arg::Address(i)) => program.address_to_index(i).unwrap(),
-                    _ => bail!("parsing failed while trying to find end jump for if statement"),
+                    Instruction::Jmp(Arg::Address(i)) => program
+                        .address_to_index(i)
+                        .ok_or_else(|| unsupported(jmp_loc, "jump to outside the program"))?,
+                    _ => bail!(unsupported(
+                        jmp_loc,
+                        "expected the jump to the end of an if"
+                    )),
                 };
 
-                let expr_if_false = parse_expr(
-                    program,
-                    program.address_to_index(if_false).unwrap(),
-                    Some(if_end),
-                    stack
-                )?
-                .0;
+                let (expr_if_false, origin_if_false, _) =
+                    parse_expr(program, false_start, Some(if_end), stack)?;
 
-                let v = expr_list.pop();
+                let (v, origin) = expr_list
+                    .pop()
+                    .ok_or_else(|| unsupported(pos, "if without a condition"))?;
                 (
-                    Expr::If(
-                        Box::new(v.unwrap()),
-                        Box::new(expr_if_true),
-                        Box::new(expr_if_false),
-                    ),
+                    Expr::If(Box::new(v), Box::new(expr_if_true), Box::new(expr_if_false)),
+                    Origin {
+                        instructions: origin.instructions.start..if_end,
+                        own: vec![pos..pos + 2, jmp_loc..jmp_loc + 1],
+                        children: vec![origin, origin_if_true, origin_if_false],
+                    },
                     if_end,
                 )
             }
-            _ => (Expr::Unknown, pos),
+            _ => (
+                Expr::Unknown,
+                Origin {
+                    instructions: pos..pos,
+                    own: Vec::new(),
+                    children: Vec::new(),
+                },
+                pos,
+            ),
         };
 
         pos = new_pos;
@@ -191,41 +345,106 @@ pub fn parse_expr(
                 break;
             }
             _ => {
-                expr_list.push(expr);
+                expr_list.push((expr, origin));
             }
         }
     }
 
-    let mut expr = expr_list.pop().unwrap();
-    while expr_list.len() > 0 {
-        expr = Expr::Begin(Box::new(expr_list.pop().unwrap()), Box::new(expr));
-    }
-
-    Ok((expr, pos))
+    let (expr, origin) = sequence(expr_list, pos);
+    Ok((expr, origin, pos))
 }
 
-pub fn parse_defines(program: &A86Program, position: usize) -> (Vec<Defn>, usize) {
+pub fn parse_defines(_program: &A86Program, position: usize) -> (Vec<Defn>, usize) {
     // TODO: implement this
 
     (Vec::new(), position + 1) // skip add rbx
 }
 
+/// Where each part of a decompiled program came from
+#[derive(Debug, Clone, PartialEq)]
+pub struct Origins {
+    pub defines: Vec<Origin>,
+    pub expr: Origin,
+}
+
 pub fn parse(program: &A86Program) -> Result<LootProgram> {
-    let (defines, expr_start) = match program.instructions()[0..3] {
+    Ok(parse_with_origins(program)?.0)
+}
+
+/// The index of the first epilogue at or after `position`: the stack is
+/// restored, the callee-saved registers popped, and the program returns
+fn epilogue(program: &A86Program, position: usize) -> Option<usize> {
+    let instructions = program.instructions();
+    (position..instructions.len()).find(|&i| {
+        matches!(
+            instructions[i..],
+            [
+                Instruction::Add(Arg::Register(Register::Rsp), Arg::Literal(_)),
+                Instruction::Pop(Arg::Register(Register::R15)),
+                Instruction::Pop(Arg::Register(Register::Rbx)),
+                Instruction::Ret,
+                ..
+            ]
+        )
+    })
+}
+
+pub fn parse_with_origins(program: &A86Program) -> Result<(LootProgram, Origins)> {
+    let entry = program
+        .address_to_index(program.entry_point())
+        .context("entry point isn't an instruction")?;
+    let (defines, expr_start) = match program.instructions()[entry..] {
         [
             Instruction::Push(Arg::Register(Register::Rbx)),
             Instruction::Push(Arg::Register(Register::R15)),
             Instruction::Mov(Arg::Register(Register::Rbx), Arg::Register(Register::Rdi)),
-        ] => parse_defines(program, 3),
+            ..,
+        ] => parse_defines(program, entry + 3),
         _ => bail!("Unable to parse loot program"),
     };
-    let end = program
-        .address_to_index(program.symbol_to_address("err").unwrap())
-        .unwrap()
-        - 4;
+    let end = epilogue(program, expr_start).context("couldn't find the end of the program")?;
     let mut stack = Vec::new();
-    Ok(LootProgram {
+    // `parse_expr` builds expressions in normal form already
+    let (expr, origin, _) = parse_expr(program, expr_start, Some(end), &mut stack)?;
+    let loot_program = LootProgram {
         defines,
-        expr: Box::new(parse_expr(program, expr_start, Some(end), &mut stack)?.0),
-    })
+        expr: Box::new(expr),
+        names: Names::new(),
+    };
+    let origins = Origins {
+        defines: Vec::new(),
+        expr: origin,
+    };
+    Ok((loot_program, origins))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nasm;
+
+    #[test]
+    fn constants_moved_into_eax_are_zero_extended() {
+        // nasm writes `mov rax, 0x80000000` as `mov eax, 0x80000000`
+        let binary = nasm::parse(
+            "global entry
+             entry:
+                 push rbx
+                 push r15
+                 mov rbx, rdi
+                 add rbx, 0
+                 mov rax, 0x80000000
+                 add rsp, 0
+                 pop r15
+                 pop rbx
+                 ret",
+        )
+        .unwrap();
+        assert_eq!(
+            binary.instructions()[4],
+            Instruction::Mov(Arg::Register(Register::Eax), Arg::Literal(-0x80000000))
+        );
+        let program = parse(&binary).unwrap();
+        assert_eq!(program.to_string(), "#lang racket\n134217728");
+    }
 }
@@ -0,0 +1,84 @@
+use std::fmt::Write;
+
+use crate::a86::Program as A86Program;
+
+/*
+  The decoded instructions, i.e. the decompiler's input, for `disasm`. Each
+  instruction is shown with its address, in NASM syntax, a86 syntax (as in
+  the course's Racket library), or both side by side. Bytes between
+  instructions that no code reaches are noted where they'd be.
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Syntax {
+    Nasm,
+    A86,
+    Both,
+}
+
+/// The listing of `binary`'s instructions in `syntax`, with their labels
+pub fn disassemble(binary: &A86Program, syntax: Syntax) -> String {
+    let label = |address| binary.label(address);
+
+    let mut out = String::new();
+    for (index, instruction) in binary.instructions().iter().enumerate() {
+        let address = binary.index_to_address(index).unwrap();
+        if let Some(gap) = binary.unreachable().iter().find(|gap| gap.end == address) {
+            writeln!(
+                out,
+                "  ; {:#x}: {} unreachable byte(s)",
+                gap.start,
+                gap.end - gap.start
+            )
+            .unwrap();
+        }
+        let mut labels: Vec<String> = binary.address_to_symbols(address).into_iter().collect();
+        labels.sort();
+        for label in labels {
+            match syntax {
+                Syntax::A86 => writeln!(out, "  (Label '{})", label),
+                _ => writeln!(out, "{}:", label),
+            }
+            .unwrap();
+        }
+
+        let line = format!("  {:#x}  ", address);
+        match syntax {
+            Syntax::Nasm => writeln!(out, "{}{}", line, instruction),
+            Syntax::A86 => writeln!(out, "{}{}", line, instruction.to_a86(label)),
+            Syntax::Both => writeln!(
+                out,
+                "{:<44}{}",
+                format!("{}{}", line, instruction),
+                instruction.to_a86(label)
+            ),
+        }
+        .unwrap();
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shows_both_syntaxes() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/test-programs/add1.run");
+        let binary = A86Program::from_elf_file(path).unwrap();
+        let entry = binary.entry_point();
+
+        let nasm = disassemble(&binary, Syntax::Nasm);
+        assert!(nasm.starts_with(&format!("entry:\n  {:#x}  push rbx\n", entry)));
+        assert!(nasm.contains("add rax, 0x10\n"), "{}", nasm);
+
+        let a86 = disassemble(&binary, Syntax::A86);
+        assert!(a86.starts_with(&format!("  (Label 'entry)\n  {:#x}  (Push 'rbx)\n", entry)));
+        assert!(a86.contains("(Add 'rax 16)\n"), "{}", a86);
+        assert!(a86.contains("(Call 'raise_error)\n"), "{}", a86);
+
+        let both = disassemble(&binary, Syntax::Both);
+        let line = both.lines().find(|l| l.contains("(Add 'rax 16)")).unwrap();
+        assert!(line.contains("add rax, 0x10"), "{}", both);
+    }
+}
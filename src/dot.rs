@@ -0,0 +1,151 @@
+use std::fmt::Write;
+
+use crate::{
+    a86::Program as A86Program,
+    cfg::Cfg,
+    decompiler::Origins,
+    loot::{Defn, Expr, Names, Program as LootProgram, name},
+    provenance::Provenance,
+};
+
+/*
+  Graphviz output (`--format dot`) of the instruction-level control-flow graph
+  and of the decompiled AST, for seeing how control flow was structured.
+  Render with e.g. `dot -Tsvg`.
+*/
+
+/// `text` as the inside of a DOT string
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// The control-flow graph of `binary`, with each block labelled with its
+/// symbols and its instructions in a86 syntax. Edges into `err` are dashed.
+pub fn cfg(binary: &A86Program) -> String {
+    let cfg = Cfg::build(binary);
+    let is_err = |block: usize| {
+        let start = cfg.blocks[block].instructions.start;
+        binary
+            .index_to_address(start)
+            .is_some_and(|address| binary.address_to_symbols(address).contains("err"))
+    };
+
+    let mut out = String::from("digraph cfg {\n  node [shape=box, fontname=monospace];\n");
+    for (id, block) in cfg.blocks.iter().enumerate() {
+        let mut label = String::new();
+        let start = binary.index_to_address(block.instructions.start).unwrap();
+        let mut symbols: Vec<String> = binary.address_to_symbols(start).into_iter().collect();
+        symbols.sort();
+        for symbol in symbols {
+            label += &format!("{}:\\l", escape(&symbol));
+        }
+        for index in block.instructions.clone() {
+            let instruction = binary.instructions()[index];
+            let text = instruction.to_a86(|address| binary.label(address));
+            label += &format!("  {}\\l", escape(&text));
+        }
+        let style = if is_err(id) { ", color=red" } else { "" };
+        writeln!(out, "  b{} [label=\"{}\"{}];", id, label, style).unwrap();
+        for &successor in &block.successors {
+            let style = if is_err(successor) {
+                " [style=dashed, color=red]"
+            } else {
+                ""
+            };
+            writeln!(out, "  b{} -> b{}{};", id, successor, style).unwrap();
+        }
+    }
+    out + "}\n"
+}
+
+/// What the node for `expr` says about it, without its subexpressions
+fn head(expr: &Expr, names: &Names) -> String {
+    match expr {
+        Expr::Literal(datum) => datum.to_string(),
+        Expr::Op(o) => o.name().to_owned(),
+        Expr::If(..) => "if".to_owned(),
+        Expr::Begin(_) => "begin".to_owned(),
+        Expr::Let(id, _, _) => format!("let {}", name(names, *id)),
+        Expr::Var(id) => name(names, *id),
+        Expr::App(..) => "app".to_owned(),
+        Expr::Match(_, patterns, _) => {
+            let patterns: Vec<String> = patterns.iter().map(|p| p.to_source(names)).collect();
+            format!("match {}", patterns.join(" | "))
+        }
+        Expr::Lam(_, params, _) => {
+            let params: Vec<String> = params.iter().map(|&p| name(names, p)).collect();
+            format!("lambda ({})", params.join(" "))
+        }
+        Expr::Unknown => "Unknown".to_owned(),
+    }
+}
+
+/// The AST of `program`, numbered as in the JSON output, with each node
+/// labelled with the addresses it was decompiled from
+pub fn ast(program: &LootProgram, origins: &Origins, binary: &A86Program) -> String {
+    let provenance = Provenance::new(program, origins);
+    let names = program.display_names();
+
+    let mut out = String::from("digraph ast {\n  node [shape=box, fontname=monospace];\n");
+    for node in provenance.nodes() {
+        let mut label = escape(&head(node.expr, &names));
+        if let Some(addresses) = provenance.addresses(node.id, binary) {
+            label += &format!("\\n{:#x}..{:#x}", addresses.start, addresses.end);
+        }
+        let style = match node.expr {
+            Expr::Unknown => ", color=red",
+            _ => "",
+        };
+        writeln!(out, "  n{} [label=\"{}\"{}];", node.id, label, style).unwrap();
+        if let Some(parent) = node.parent {
+            writeln!(out, "  n{} -> n{};", parent, node.id).unwrap();
+        }
+    }
+
+    // Definitions point at their bodies, which come first among the nodes
+    let mut body = 0;
+    for (i, Defn(id, params, expr)) in program.defines.iter().enumerate() {
+        let mut header = vec![name(&names, *id)];
+        header.extend(params.iter().map(|&p| name(&names, p)));
+        writeln!(
+            out,
+            "  d{} [label=\"define ({})\", shape=ellipse];\n  d{} -> n{};",
+            i,
+            escape(&header.join(" ")),
+            i,
+            body
+        )
+        .unwrap();
+        body += node_count(expr);
+    }
+    out + "}\n"
+}
+
+fn node_count(expr: &Expr) -> usize {
+    1 + expr.children().into_iter().map(node_count).sum::<usize>()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decompiler::parse_with_origins;
+
+    #[test]
+    fn exports_cfg_and_ast() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/test-programs/add1.run");
+        let binary = A86Program::from_elf_file(path).unwrap();
+
+        let cfg = cfg(&binary);
+        assert!(cfg.starts_with("digraph cfg {\n"));
+        assert!(cfg.contains("entry:\\l  (Push 'rbx)\\l"), "{}", cfg);
+        // The type checks of the add1s may jump to err
+        assert!(cfg.contains("[style=dashed, color=red];"), "{}", cfg);
+
+        let (program, origins) = parse_with_origins(&binary).unwrap();
+        let ast = ast(&program, &origins, &binary);
+        assert!(ast.contains("  n0 [label=\"begin\\n0x"), "{}", ast);
+        assert!(ast.contains("  n1 [label=\"add1\\n0x"), "{}", ast);
+        assert!(ast.contains("  n0 -> n1;\n"));
+        assert!(ast.ends_with("}\n"));
+    }
+}
@@ -0,0 +1,418 @@
+use anyhow::{Context, Result};
+
+use crate::{
+    a86::{Address, Arg, Instruction, Register},
+    assembler::Assembler,
+};
+
+/// Runtime functions that compiled programs call into, and that get laid out
+/// as stubs after the program's code
+pub const RUNTIME_FUNCTIONS: &[&str] = &["read_byte", "peek_byte", "write_byte", "raise_error"];
+
+/// Where the (single) loadable segment gets mapped
+const BASE_ADDRESS: Address = 0x400000;
+/// File offset of the .text section, page-aligned so it can be mapped directly
+const TEXT_OFFSET: usize = 0x1000;
+const TEXT_ADDRESS: Address = BASE_ADDRESS + TEXT_OFFSET as Address;
+
+const EHDR_SIZE: u16 = 64;
+const PHDR_SIZE: u16 = 56;
+const SHDR_SIZE: u16 = 64;
+const SYM_SIZE: u64 = 24;
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHF_ALLOC: u64 = 0x2;
+const SHF_EXECINSTR: u64 = 0x4;
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+const STT_NOTYPE: u8 = 0;
+const STT_FUNC: u8 = 2;
+
+/// Stub for a runtime function: `read_byte` and `peek_byte` report eof
+/// (the encoding of `eof`), everything else just returns
+fn runtime_stub(name: &str) -> Vec<Instruction> {
+    match name {
+        "read_byte" | "peek_byte" => vec![
+            Instruction::Mov(Arg::Register(Register::Rax), Arg::Literal(0b1011000)),
+            Instruction::Ret,
+        ],
+        _ => vec![Instruction::Ret],
+    }
+}
+
+struct Symbol {
+    name: String,
+    address: Address,
+    size: u64,
+    global: bool,
+    function: bool,
+}
+
+/// Little-endian byte buffer with helpers for ELF64 structures
+#[derive(Default)]
+struct Image(Vec<u8>);
+
+impl Image {
+    fn u8(&mut self, v: u8) {
+        self.0.push(v);
+    }
+    fn u16(&mut self, v: u16) {
+        self.0.extend_from_slice(&v.to_le_bytes());
+    }
+    fn u32(&mut self, v: u32) {
+        self.0.extend_from_slice(&v.to_le_bytes());
+    }
+    fn u64(&mut self, v: u64) {
+        self.0.extend_from_slice(&v.to_le_bytes());
+    }
+    fn align(&mut self, alignment: usize) {
+        while !self.0.len().is_multiple_of(alignment) {
+            self.0.push(0);
+        }
+    }
+    fn len(&self) -> u64 {
+        self.0.len() as u64
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn section_header(
+        &mut self,
+        name: u32,
+        kind: u32,
+        flags: u64,
+        address: Address,
+        offset: u64,
+        size: u64,
+        link: u32,
+        info: u32,
+        alignment: u64,
+        entry_size: u64,
+    ) {
+        self.u32(name);
+        self.u32(kind);
+        self.u64(flags);
+        self.u64(address);
+        self.u64(offset);
+        self.u64(size);
+        self.u32(link);
+        self.u32(info);
+        self.u64(alignment);
+        self.u64(entry_size);
+    }
+}
+
+/// A NUL-separated string table, as used by .strtab and .shstrtab
+struct StringTable(Vec<u8>);
+
+impl StringTable {
+    fn new() -> Self {
+        Self(vec![0])
+    }
+
+    fn add(&mut self, s: &str) -> u32 {
+        let offset = self.0.len() as u32;
+        self.0.extend_from_slice(s.as_bytes());
+        self.0.push(0);
+        offset
+    }
+}
+
+/// Pushes the course compiler's prologue, at `entry`: the callee-saved
+/// registers are saved and the heap pointer is taken from `rdi`
+pub fn push_prologue(asm: &mut Assembler) {
+    use crate::a86::{Arg::*, Instruction::*, Register::*};
+    asm.bind("entry").unwrap();
+    asm.push(Push(Register(Rbx)));
+    asm.push(Push(Register(R15)));
+    asm.push(Mov(Register(Rbx), Register(Rdi)));
+    asm.push(Add(Register(Rbx), Literal(0)));
+}
+
+/// Pushes the epilogue that returns from `entry`
+pub fn push_epilogue(asm: &mut Assembler) {
+    use crate::a86::{Arg::*, Instruction::*, Register::*};
+    asm.push(Add(Register(Rsp), Literal(0)));
+    asm.push(Pop(Register(R15)));
+    asm.push(Pop(Register(Rbx)));
+    asm.push(Ret);
+}
+
+/// A program laid out as the course compiler does: what `body` pushes,
+/// between the prologue and the epilogue, then `err`, which aligns the stack
+/// and calls `raise_error`
+pub fn course_program(body: impl FnOnce(&mut Assembler)) -> Assembler {
+    use crate::a86::{Arg::*, Instruction::*, Register::*};
+    let mut asm = Assembler::new();
+    push_prologue(&mut asm);
+    body(&mut asm);
+    push_epilogue(&mut asm);
+    let raise_error = asm.label("raise_error");
+    asm.bind("err").unwrap();
+    asm.push(Mov(Register(R15), Register(Rsp)));
+    asm.push(And(Register(R15), Literal(0x8)));
+    asm.push(Sub(Register(Rsp), Register(R15)));
+    asm.push(Call(raise_error));
+    asm
+}
+
+/// Lays out `program` (which should bind `entry` and `err`, like the course
+/// compiler's output does) followed by stubs for the runtime functions, and
+/// writes it as a statically-positioned ELF64 executable.
+///
+/// Every bound label ends up in `.symtab`: `entry` and the runtime functions
+/// as globals, everything else as locals.
+pub fn write_elf(mut program: Assembler) -> Result<Vec<u8>> {
+    let mut stub_ranges = Vec::new();
+    for &name in RUNTIME_FUNCTIONS {
+        program.bind(name)?;
+        let start = program.instructions().len();
+        for instruction in runtime_stub(name) {
+            program.push(instruction);
+        }
+        stub_ranges.push((name, start, program.instructions().len()));
+    }
+
+    let assembly = program.assemble(TEXT_ADDRESS)?;
+    let entry = *assembly
+        .labels
+        .get("entry")
+        .context("program does not bind an entry label")?;
+    let end = TEXT_ADDRESS + assembly.code.len() as Address;
+
+    let mut symbols: Vec<Symbol> = assembly
+        .labels
+        .iter()
+        .map(|(name, &address)| {
+            let stub = stub_ranges.iter().find(|(stub, ..)| stub == name);
+            let size = match stub {
+                Some(&(_, _, stop)) => {
+                    assembly.addresses.get(stop).copied().unwrap_or(end) - address
+                }
+                None => 0,
+            };
+            Symbol {
+                name: name.clone(),
+                address,
+                size,
+                global: name == "entry" || stub.is_some(),
+                function: stub.is_some(),
+            }
+        })
+        .collect();
+    // Locals must precede globals in the symbol table
+    symbols.sort_by(|a, b| (a.global, a.address, &a.name).cmp(&(b.global, b.address, &b.name)));
+    let first_global = 1 + symbols.iter().filter(|s| !s.global).count() as u32;
+
+    let mut image = Image::default();
+
+    // ELF header
+    image.0.extend_from_slice(b"\x7fELF");
+    image.u8(2); // ELFCLASS64
+    image.u8(1); // ELFDATA2LSB
+    image.u8(1); // EV_CURRENT
+    image.align(16);
+    image.u16(2); // ET_EXEC
+    image.u16(62); // EM_X86_64
+    image.u32(1);
+    image.u64(entry);
+    image.u64(EHDR_SIZE as u64);
+    let shoff_position = image.0.len();
+    image.u64(0); // e_shoff, patched below
+    image.u32(0);
+    image.u16(EHDR_SIZE);
+    image.u16(PHDR_SIZE);
+    image.u16(1);
+    image.u16(SHDR_SIZE);
+    image.u16(5);
+    image.u16(4);
+
+    // A single read + execute segment covering the headers and .text
+    let segment_size = (TEXT_OFFSET + assembly.code.len()) as u64;
+    image.u32(1); // PT_LOAD
+    image.u32(0x5); // PF_R | PF_X
+    image.u64(0);
+    image.u64(BASE_ADDRESS);
+    image.u64(BASE_ADDRESS);
+    image.u64(segment_size);
+    image.u64(segment_size);
+    image.u64(0x1000);
+
+    image.align(TEXT_OFFSET);
+    image.0.extend_from_slice(&assembly.code);
+
+    let mut strtab = StringTable::new();
+    image.align(8);
+    let symtab_offset = image.len();
+    image.0.extend_from_slice(&[0; SYM_SIZE as usize]);
+    for symbol in &symbols {
+        let name = strtab.add(&symbol.name);
+        let binding = if symbol.global { STB_GLOBAL } else { STB_LOCAL };
+        let kind = if symbol.function {
+            STT_FUNC
+        } else {
+            STT_NOTYPE
+        };
+        image.u32(name);
+        image.u8(binding << 4 | kind);
+        image.u8(0);
+        image.u16(1); // .text
+        image.u64(symbol.address);
+        image.u64(symbol.size);
+    }
+    let symtab_size = image.len() - symtab_offset;
+
+    let strtab_offset = image.len();
+    image.0.extend_from_slice(&strtab.0);
+
+    let mut shstrtab = StringTable::new();
+    let text_name = shstrtab.add(".text");
+    let symtab_name = shstrtab.add(".symtab");
+    let strtab_name = shstrtab.add(".strtab");
+    let shstrtab_name = shstrtab.add(".shstrtab");
+    let shstrtab_offset = image.len();
+    image.0.extend_from_slice(&shstrtab.0);
+
+    image.align(8);
+    let shoff = image.len();
+    image.0[shoff_position..shoff_position + 8].copy_from_slice(&shoff.to_le_bytes());
+    image.section_header(0, 0, 0, 0, 0, 0, 0, 0, 0, 0);
+    image.section_header(
+        text_name,
+        SHT_PROGBITS,
+        SHF_ALLOC | SHF_EXECINSTR,
+        TEXT_ADDRESS,
+        TEXT_OFFSET as u64,
+        assembly.code.len() as u64,
+        0,
+        0,
+        16,
+        0,
+    );
+    image.section_header(
+        symtab_name,
+        SHT_SYMTAB,
+        0,
+        0,
+        symtab_offset,
+        symtab_size,
+        3,
+        first_global,
+        8,
+        SYM_SIZE,
+    );
+    image.section_header(
+        strtab_name,
+        SHT_STRTAB,
+        0,
+        0,
+        strtab_offset,
+        strtab.0.len() as u64,
+        0,
+        0,
+        1,
+        0,
+    );
+    image.section_header(
+        shstrtab_name,
+        SHT_STRTAB,
+        0,
+        0,
+        shstrtab_offset,
+        shstrtab.0.len() as u64,
+        0,
+        0,
+        1,
+        0,
+    );
+
+    Ok(image.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use elf::{ElfBytes, endian::AnyEndian};
+
+    use super::*;
+    use crate::{
+        a86::{Arg::*, Instruction::*, Program, Register::*},
+        decompiler::parse,
+    };
+
+    fn write_fixture(name: &str, assembler: Assembler) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "compiler-deconstruction-{}-{name}.run",
+            std::process::id()
+        ));
+        fs::write(&path, write_elf(assembler).unwrap()).unwrap();
+        path
+    }
+
+    /// `(add1 (if #t 160 999))`, as the course compiler lays it out
+    fn add1_if() -> Assembler {
+        course_program(|asm| {
+            let (if_false, if_end, err) = (asm.label("if1"), asm.label("if2"), asm.label("err"));
+            asm.push(Mov(Register(Rax), Literal(0x18)));
+            asm.push(Cmp(Register(Rax), Literal(0x38)));
+            asm.push(Je(Address(if_false)));
+            asm.push(Mov(Register(Rax), Literal(0xa00)));
+            asm.push(Jmp(Address(if_end)));
+            asm.bind("if1").unwrap();
+            asm.push(Mov(Register(Rax), Literal(0x3e70)));
+            asm.bind("if2").unwrap();
+            asm.push(Mov(Register(R9), Register(Rax)));
+            asm.push(And(Register(R9), Literal(0xf)));
+            asm.push(Cmp(Register(R9), Literal(0)));
+            asm.push(Jne(Address(err)));
+            asm.push(Add(Register(Rax), Literal(0x10)));
+        })
+    }
+
+    #[test]
+    fn fixture_loads_and_decompiles() {
+        let path = write_fixture("add1-if", add1_if());
+        let program = Program::from_elf_file(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let err = program.symbol_to_address("err").unwrap();
+        assert_eq!(
+            program.entry_point(),
+            program.symbol_to_address("entry").unwrap()
+        );
+        assert!(program.address_to_symbols(err).contains("err"));
+        for &name in RUNTIME_FUNCTIONS {
+            assert!(program.symbol_to_address(name).is_some(), "missing {name}");
+        }
+        assert_eq!(program.instructions()[4], Mov(Register(Eax), Literal(0x18)));
+        assert_eq!(
+            program.instructions()[6],
+            Je(Address(program.symbol_to_address("if1").unwrap()))
+        );
+
+        assert_eq!(
+            parse(&program).unwrap().to_string(),
+            "#lang racket\n(add1 (if #t 160 999))"
+        );
+    }
+
+    #[test]
+    fn encoding_matches_nasm() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/test-programs/add.run");
+        let program = Program::from_elf_file(path).unwrap();
+
+        let mut asm = Assembler::new();
+        for &instruction in program.instructions() {
+            asm.push(instruction);
+        }
+        let assembly = asm.assemble(program.entry_point()).unwrap();
+
+        let bytes = fs::read(path).unwrap();
+        let file = ElfBytes::<AnyEndian>::minimal_parse(&bytes).unwrap();
+        let text = file.section_header_by_name(".text").unwrap().unwrap();
+        let start = (text.sh_offset + program.entry_point() - text.sh_addr) as usize;
+        assert_eq!(assembly.code, &bytes[start..start + assembly.code.len()]);
+    }
+}
@@ -0,0 +1,104 @@
+/*
+  Golden tests over test-programs/: every `foo.run` is decompiled and compared
+  against `foo.rkt`, modulo renaming of variables and the nesting of `begin`s
+  (which the compiled code does not preserve, see `Expr::normalize`).
+
+  Binaries without a `.rkt` (their source is lost, as for `const.run` and
+  `if.run`) only have to decompile. Expectations are Racket sources, so they
+  aren't made up from the decompiler's output for these.
+
+  Run with BLESS=1 to write the decompiler's output to the `.rkt` files of
+  programs that no longer match.
+*/
+
+use std::{env, fs, path::Path};
+
+use anyhow::{Context, Result};
+
+use crate::{
+    a86::Program as A86Program, alpha::diff_programs, decompiler::parse,
+    loot::Program as LootProgram, parser::parse_program,
+};
+
+/// Parses and normalizes Loot source, so that it can be compared with the
+/// (normalized) output of the decompiler
+fn parse_source(source: &str) -> Result<LootProgram> {
+    let mut program = parse_program(source)?;
+    program.normalize();
+    Ok(program)
+}
+
+fn decompile(path: &Path) -> Result<LootProgram> {
+    let program = A86Program::from_elf_file(path)?;
+    parse(&program)
+}
+
+/// Checks one binary against its expectation, returning a description of the
+/// mismatch if there is one
+fn check(binary: &Path, bless: bool) -> Result<Option<String>> {
+    let decompiled = decompile(binary)?;
+    let expected_path = binary.with_extension("rkt");
+
+    let Ok(expected) = fs::read_to_string(&expected_path) else {
+        return Ok(None);
+    };
+    let expected = parse_source(&expected)
+        .with_context(|| format!("failed to parse {}", expected_path.display()))?;
+    let mismatch = diff_programs(&expected, &decompiled).map(|difference| difference.to_string());
+
+    match mismatch {
+        None => Ok(None),
+        Some(_) if bless => {
+            fs::write(&expected_path, format!("{}\n", decompiled))?;
+            Ok(None)
+        }
+        Some(difference) => Ok(Some(format!(
+            "{} does not match {} ({}); decompiled to:\n{}",
+            binary.display(),
+            expected_path.display(),
+            difference,
+            decompiled
+        ))),
+    }
+}
+
+#[test]
+fn test_programs_decompile_to_their_sources() {
+    let bless = env::var_os("BLESS").is_some();
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("test-programs");
+
+    let mut binaries: Vec<_> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "run"))
+        .collect();
+    binaries.sort();
+    assert!(
+        !binaries.is_empty(),
+        "no test programs in {}",
+        dir.display()
+    );
+
+    let failures: Vec<String> = binaries
+        .iter()
+        .filter_map(|binary| match check(binary, bless) {
+            Ok(mismatch) => mismatch,
+            Err(e) => Some(format!("{}: {:#}", binary.display(), e)),
+        })
+        .collect();
+    assert!(
+        failures.is_empty(),
+        "{}\n\n(run with BLESS=1 to update expectations)",
+        failures.join("\n\n")
+    );
+}
+
+#[test]
+fn begin_nesting_is_insignificant() {
+    let a = parse_source("(begin (begin (f x) (g x)) 1 (let ([y 1]) y))").unwrap();
+    let b = parse_source("(begin (var0 var1) (begin (var2 var1) (let ([var3 1]) var3)))").unwrap();
+    assert_eq!(diff_programs(&a, &b), None);
+
+    let c = parse_source("(begin (var0 var1) (var2 var2) (let ([var3 1]) var3))").unwrap();
+    assert!(diff_programs(&a, &c).is_some());
+}
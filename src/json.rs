@@ -0,0 +1,325 @@
+use serde_json::{Value, json};
+
+use crate::{
+    a86::{Arg, Program as A86Program},
+    cfg::Cfg,
+    decompiler::{Origin, Origins},
+    loot::{Datum, Defn, Expr, Id, Names, Program as LootProgram},
+};
+
+/*
+  Machine-readable output (`--format json`). The schema is versioned, and
+  fields are only ever added within a version:
+
+  {
+    "version": 1,
+    "program": {
+      "defines": [Define],
+      "expr": Node
+    },
+    "instructions": [Instruction],  only with `--include instructions`
+    "symbols": [Symbol],            only with `--include symbols`
+    "cfg": { "blocks": [Block] }    only with `--include cfg`
+  }
+
+  Define      { "id": Id, "name": string, "params": [Variable], "body": Node }
+  Variable    { "id": Id, "name": string }
+  Node        { "id": int, "kind": string, "start": Address | null,
+                "end": Address | null, "children": [Node], ...by kind }
+    Nodes are numbered in pre-order, definitions first. `start` and `end`
+    are the addresses of the first instruction the node was decompiled from
+    and of the one after its last, or null when that's unknown. The kinds are
+      "literal"  "value": string (Racket syntax), "type": "integer" | "boolean"
+                 | "char" | "string" | "eof" | "empty"
+      "op"       "name": string (the primitive, as in Racket)
+      "if", "begin", "app", "unknown"
+      "let"      "var": Variable (the children are the bound expression and body)
+      "var"      "var": Variable
+      "match"    "patterns": [string] (one per body; the scrutinee comes first
+                 among the children)
+      "lambda"   "lambda": Id, "params": [Variable]
+  Instruction { "index": int, "address": Address, "mnemonic": string,
+                "operands": [Operand] }
+  Operand     { "kind": "register", "register": string }
+              | { "kind": "literal", "value": int }
+              | { "kind": "address", "address": Address }
+              | { "kind": "memory", "base": string | null, "index": string | null,
+                  "scale": int, "displacement": int, "rip_relative": bool,
+                  "size": int }
+                (for a RIP-relative operand, the displacement is the address;
+                the size is in bytes, or 0 where only the address is taken)
+  Symbol      { "name": string, "address": Address }, ordered by address
+  Block       { "id": int, "start": Address, "end": Address,
+                "instructions": [int, int] (a half-open range of indices),
+                "successors": [int] (fall-through first) }
+
+  Addresses and `Id`s are non-negative integers.
+*/
+
+pub const VERSION: u64 = 1;
+
+/// Optional sections of the output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Section {
+    Instructions,
+    Symbols,
+    Cfg,
+}
+
+struct Exporter<'a> {
+    binary: &'a A86Program,
+    names: Names,
+    next_id: usize,
+}
+
+impl Exporter<'_> {
+    /// The address of the instruction at `index`, which may be one past the end
+    /// of the program
+    fn address(&self, index: usize) -> Option<u64> {
+        self.binary.index_to_address(index)
+    }
+
+    fn variable(&self, id: Id) -> Value {
+        let name = self
+            .names
+            .get(&id)
+            .cloned()
+            .unwrap_or_else(|| format!("var{}", id));
+        json!({ "id": id, "name": name })
+    }
+
+    fn node(&mut self, expr: &Expr, origin: Option<&Origin>) -> Value {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let mut node = json!({
+            "id": id,
+            "start": origin.and_then(|o| self.address(o.instructions.start)),
+            "end": origin.and_then(|o| self.address(o.instructions.end)),
+        });
+        let (kind, fields) = match expr {
+            Expr::Literal(datum) => {
+                let ty = match datum {
+                    Datum::Integer(_) => "integer",
+                    Datum::Boolean(_) => "boolean",
+                    Datum::Character(_) => "char",
+                    Datum::String(_) => "string",
+                    Datum::Eof => "eof",
+                    Datum::Empty => "empty",
+                };
+                ("literal", json!({ "value": datum.to_string(), "type": ty }))
+            }
+            Expr::Op(o) => ("op", json!({ "name": o.name() })),
+            Expr::If(..) => ("if", json!({})),
+            Expr::Begin(_) => ("begin", json!({})),
+            Expr::Let(var, _, _) => ("let", json!({ "var": self.variable(*var) })),
+            Expr::Var(var) => ("var", json!({ "var": self.variable(*var) })),
+            Expr::App(..) => ("app", json!({})),
+            Expr::Match(_, patterns, _) => {
+                let patterns: Vec<String> =
+                    patterns.iter().map(|p| p.to_source(&self.names)).collect();
+                ("match", json!({ "patterns": patterns }))
+            }
+            Expr::Lam(lambda, params, _) => {
+                let params: Vec<Value> = params.iter().map(|&p| self.variable(p)).collect();
+                ("lambda", json!({ "lambda": lambda, "params": params }))
+            }
+            Expr::Unknown => ("unknown", json!({})),
+        };
+        node["kind"] = json!(kind);
+        if let Value::Object(fields) = fields {
+            node.as_object_mut().unwrap().extend(fields);
+        }
+
+        let children: Vec<Value> = expr
+            .children()
+            .into_iter()
+            .enumerate()
+            .map(|(i, child)| {
+                let origin = origin.and_then(|o| o.children.get(i));
+                self.node(child, origin)
+            })
+            .collect();
+        node["children"] = json!(children);
+        node
+    }
+
+    fn define(&mut self, defn: &Defn, origin: Option<&Origin>) -> Value {
+        let Defn(id, params, body) = defn;
+        let params: Vec<Value> = params.iter().map(|&p| self.variable(p)).collect();
+        json!({
+            "id": id,
+            "name": self.variable(*id)["name"],
+            "params": params,
+            "body": self.node(body, origin),
+        })
+    }
+}
+
+fn operand(arg: &Arg) -> Value {
+    let register = |r| format!("{:?}", r).to_lowercase();
+    match *arg {
+        Arg::Register(r) => json!({ "kind": "register", "register": register(r) }),
+        Arg::Literal(value) => json!({ "kind": "literal", "value": value }),
+        Arg::Address(address) => json!({ "kind": "address", "address": address }),
+        Arg::Memory(memory) => json!({
+            "kind": "memory",
+            "base": memory.base.map(register),
+            "index": memory.index.map(register),
+            "scale": memory.scale,
+            "displacement": memory.displacement,
+            "rip_relative": memory.rip_relative,
+            "size": memory.size,
+        }),
+    }
+}
+
+/// JSON `text` with every non-ASCII character escaped. serde_json only writes
+/// them in strings, where escapes are allowed.
+pub fn to_ascii(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        if c.is_ascii() {
+            out.push(c);
+        } else {
+            for unit in c.encode_utf16(&mut [0; 2]) {
+                out += &format!("\\u{:04x}", unit);
+            }
+        }
+    }
+    out
+}
+
+/// The `instructions` section described above
+pub fn instructions(binary: &A86Program) -> Value {
+    let instructions: Vec<Value> = binary
+        .instructions()
+        .iter()
+        .enumerate()
+        .map(|(index, instruction)| {
+            let operands: Vec<Value> = instruction.operands().iter().map(operand).collect();
+            json!({
+                "index": index,
+                "address": binary.index_to_address(index),
+                "mnemonic": instruction.mnemonic(),
+                "operands": operands,
+            })
+        })
+        .collect();
+    json!(instructions)
+}
+
+/// The `symbols` section described above
+pub fn symbols(binary: &A86Program) -> Value {
+    let symbols: Vec<Value> = binary
+        .symbols()
+        .into_iter()
+        .map(|(name, address)| json!({ "name": name, "address": address }))
+        .collect();
+    json!(symbols)
+}
+
+/// The `cfg` section described above
+pub fn cfg(binary: &A86Program) -> Value {
+    let blocks: Vec<Value> = Cfg::build(binary)
+        .blocks
+        .iter()
+        .enumerate()
+        .map(|(id, block)| {
+            json!({
+                "id": id,
+                "start": binary.index_to_address(block.instructions.start),
+                "end": binary.index_to_address(block.instructions.end),
+                "instructions": [block.instructions.start, block.instructions.end],
+                "successors": block.successors,
+            })
+        })
+        .collect();
+    json!({ "blocks": blocks })
+}
+
+/// The document described above, for `program` decompiled from `binary`
+pub fn export(
+    program: &LootProgram,
+    origins: &Origins,
+    binary: &A86Program,
+    sections: &[Section],
+) -> Value {
+    let mut exporter = Exporter {
+        binary,
+        names: program.display_names(),
+        next_id: 0,
+    };
+
+    let defines: Vec<Value> = program
+        .defines
+        .iter()
+        .enumerate()
+        .map(|(i, defn)| exporter.define(defn, origins.defines.get(i)))
+        .collect();
+    let expr = exporter.node(&program.expr, Some(&origins.expr));
+    let mut document = json!({
+        "version": VERSION,
+        "program": { "defines": defines, "expr": expr },
+    });
+
+    if sections.contains(&Section::Instructions) {
+        document["instructions"] = instructions(binary);
+    }
+    if sections.contains(&Section::Symbols) {
+        document["symbols"] = symbols(binary);
+    }
+    if sections.contains(&Section::Cfg) {
+        document["cfg"] = cfg(binary);
+    }
+
+    document
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decompiler::parse_with_origins;
+
+    #[test]
+    fn exports_nodes_with_addresses() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/test-programs/add1.run");
+        let binary = A86Program::from_elf_file(path).unwrap();
+        let (program, origins) = parse_with_origins(&binary).unwrap();
+        let document = export(
+            &program,
+            &origins,
+            &binary,
+            &[Section::Symbols, Section::Cfg],
+        );
+
+        assert_eq!(document["version"], 1);
+        let expr = &document["program"]["expr"];
+        assert_eq!(expr["kind"], "begin");
+        assert_eq!(expr["id"], 0);
+        let add1 = &expr["children"][0];
+        assert_eq!(
+            (&add1["kind"], &add1["name"], &add1["id"]),
+            (&json!("op"), &json!("add1"), &json!(1))
+        );
+
+        // Children lie within their parents
+        let (start, end) = (
+            add1["start"].as_u64().unwrap(),
+            add1["end"].as_u64().unwrap(),
+        );
+        let inner = &add1["children"][0];
+        assert!(start <= inner["start"].as_u64().unwrap());
+        assert!(inner["end"].as_u64().unwrap() <= end);
+
+        let entry = document["symbols"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|s| s["name"] == "entry")
+            .unwrap();
+        assert!(entry["address"].as_u64().unwrap() < expr["start"].as_u64().unwrap());
+        assert!(document["cfg"]["blocks"].as_array().unwrap().len() > 1);
+        assert!(document.get("instructions").is_none());
+    }
+}
@@ -0,0 +1,231 @@
+//! A decompiler for programs built by the course compiler, from x86-64
+//! machine code back to Racket source.
+//!
+//! The stable API is this module's items together with the [`a86`],
+//! [`loot`] and [`decompiler`] modules: load a binary as an
+//! [`a86::Program`], then [`decompile`] it into a [`loot::Program`].
+//!
+//! ```no_run
+//! use compiler_deconstruction::{DecompileOptions, decompile_file};
+//!
+//! let decompilation = decompile_file("fact.run", &DecompileOptions::default())?;
+//! for diagnostic in &decompilation.diagnostics {
+//!     eprintln!("{}", diagnostic);
+//! }
+//! println!("{}", decompilation.program);
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+//!
+//! The other modules back the command-line tool and may change.
+
+pub mod a86;
+pub mod alpha;
+pub mod assembler;
+pub mod batch;
+pub mod cfg;
+#[cfg(test)]
+mod compiler;
+pub mod decompiler;
+pub mod disasm;
+pub mod dot;
+#[cfg(test)]
+mod elf_writer;
+#[cfg(test)]
+mod golden;
+pub mod json;
+pub mod loot;
+pub mod macho;
+pub mod naming;
+pub mod nasm;
+pub mod parser;
+pub mod pretty;
+pub mod provenance;
+pub mod sexp;
+
+use std::path::Path;
+
+use anyhow::{Result, bail};
+
+use a86::{Address, Program as A86Program};
+use decompiler::{Origin, Origins, Unsupported, parse_with_origins};
+use loot::{Expr, Program as LootProgram};
+
+/// How to decompile a program
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecompileOptions {
+    /// Succeed even if parts of the program couldn't be decompiled (they're
+    /// left as `Expr::Unknown`, with a warning). On by default.
+    pub allow_partial: bool,
+}
+
+impl Default for DecompileOptions {
+    fn default() -> Self {
+        Self {
+            allow_partial: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+/// Something worth knowing about how a decompilation went
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// The address of the instruction the diagnostic is about, if any
+    pub address: Option<Address>,
+    pub message: String,
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.severity {
+            Severity::Warning => write!(f, "warning")?,
+            Severity::Error => write!(f, "error")?,
+        }
+        if let Some(address) = self.address {
+            write!(f, " at {:#x}", address)?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+impl std::error::Error for Diagnostic {}
+
+impl From<anyhow::Error> for Diagnostic {
+    fn from(error: anyhow::Error) -> Self {
+        match error.downcast_ref::<Unsupported>() {
+            Some(unsupported) => Diagnostic {
+                severity: Severity::Error,
+                address: Some(unsupported.address),
+                message: unsupported.reason.clone(),
+            },
+            None => Diagnostic {
+                severity: Severity::Error,
+                address: None,
+                message: format!("{:#}", error),
+            },
+        }
+    }
+}
+
+/// A decompiled program, with where each part of it came from
+#[derive(Debug, Clone)]
+pub struct Decompilation {
+    pub program: LootProgram,
+    pub origins: Origins,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+/// Warnings for the `Unknown`s in `expr`
+fn unknowns(expr: &Expr, origin: &Origin, binary: &A86Program, out: &mut Vec<Diagnostic>) {
+    match expr {
+        Expr::Unknown => out.push(Diagnostic {
+            severity: Severity::Warning,
+            address: binary.index_to_address(origin.instructions.start),
+            message: "couldn't decompile the code here".to_owned(),
+        }),
+        _ => {
+            for (i, child) in expr.children().into_iter().enumerate() {
+                if let Some(origin) = origin.children.get(i) {
+                    unknowns(child, origin, binary, out);
+                }
+            }
+        }
+    }
+}
+
+/// Decompiles `binary`. Failures are errors holding a [`Diagnostic`], which
+/// says where the decompiler stopped if it didn't recognize some code.
+pub fn decompile(binary: &A86Program, options: &DecompileOptions) -> Result<Decompilation> {
+    let (program, origins) = parse_with_origins(binary).map_err(Diagnostic::from)?;
+
+    let mut diagnostics = Vec::new();
+    for (i, defn) in program.defines.iter().enumerate() {
+        if let Some(origin) = origins.defines.get(i) {
+            unknowns(&defn.2, origin, binary, &mut diagnostics);
+        }
+    }
+    unknowns(&program.expr, &origins.expr, binary, &mut diagnostics);
+    if !options.allow_partial && !diagnostics.is_empty() {
+        bail!(Diagnostic {
+            severity: Severity::Error,
+            address: None,
+            message: format!(
+                "{} part(s) of the program couldn't be decompiled",
+                diagnostics.len()
+            ),
+        });
+    }
+
+    Ok(Decompilation {
+        program,
+        origins,
+        diagnostics,
+    })
+}
+
+/// Decompiles the ELF executable or object, or Mach-O object, with contents
+/// `bytes`
+pub fn decompile_bytes(bytes: &[u8], options: &DecompileOptions) -> Result<Decompilation> {
+    decompile(&A86Program::from_bytes(bytes)?, options)
+}
+
+/// Decompiles the ELF executable or object, Mach-O object, or NASM assembly
+/// (`.s` or `.asm`), at `path`
+pub fn decompile_file(path: impl AsRef<Path>, options: &DecompileOptions) -> Result<Decompilation> {
+    decompile(&A86Program::from_file(path)?, options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decompiles_through_the_api() {
+        let bytes = include_bytes!("../test-programs/add1.run");
+        let decompilation = decompile_bytes(bytes, &DecompileOptions::default()).unwrap();
+        assert_eq!(
+            decompilation.program.to_string(),
+            "#lang racket\n(begin (add1 (add1 (if #t 160 999))) (if 1 2 (add1 3)))"
+        );
+        assert!(decompilation.diagnostics.is_empty());
+
+        let options = DecompileOptions {
+            allow_partial: false,
+        };
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/test-programs/add1.run");
+        let decompilation = decompile_file(path, &options).unwrap();
+        assert!(decompilation.diagnostics.is_empty());
+        assert!(decompile_bytes(b"\x7fELF", &options).is_err());
+
+        // A value with no type's tag
+        let binary = nasm::parse(
+            "global entry\nentry:\n push rbx\n push r15\n mov rbx, rdi\n add rbx, 0\n \
+             mov rax, 7\n add rsp, 0\n pop r15\n pop rbx\n ret\n",
+        )
+        .unwrap();
+        let error = decompile(&binary, &options).unwrap_err();
+        let diagnostic = error.downcast_ref::<Diagnostic>().unwrap();
+        assert_eq!(diagnostic.address, binary.index_to_address(4));
+        assert_eq!(
+            diagnostic.to_string(),
+            format!(
+                "error at {:#x}: unknown constant",
+                diagnostic.address.unwrap()
+            )
+        );
+        assert_eq!(diagnostic.severity, Severity::Error);
+
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/test-programs/read-byte.o");
+        let decompilation = decompile_file(path, &options).unwrap();
+        assert_eq!(
+            decompilation.program.to_string(),
+            "#lang racket\n(add1 (read-byte))"
+        );
+    }
+}
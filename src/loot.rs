@@ -1,11 +1,31 @@
-type Id = usize;
+use std::collections::HashMap;
 
-#[derive(Debug)]
+use crate::{
+    pretty::{DEFAULT_WIDTH, Doc, align, concat, group, line, lines, nest, text},
+    sexp::{char_name, string_literal},
+};
+
+pub type Id = usize;
+
+/// Source names of `Id`s, for printing. `Id`s without one print as `var<id>`
+/// (or `defn<id>` for definitions).
+pub type Names = HashMap<Id, String>;
+
+pub fn name(names: &Names, id: Id) -> String {
+    names
+        .get(&id)
+        .cloned()
+        .unwrap_or_else(|| format!("var{}", id))
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Datum {
     Integer(i64),
     Boolean(bool),
     Character(char),
     String(String),
+    Eof,
+    Empty,
 }
 
 impl std::fmt::Display for Datum {
@@ -14,13 +34,18 @@ impl std::fmt::Display for Datum {
             Datum::Integer(i) => write!(f, "{}", i),
             Datum::Boolean(true) => write!(f, "#t"),
             Datum::Boolean(false) => write!(f, "#f"),
-            Datum::Character(c) => write!(f, "#\\{}", c),
-            Datum::String(s) => write!(f, "{:?}", s),
+            Datum::Character(c) => match char_name(*c) {
+                Some(name) => write!(f, "#\\{}", name),
+                None => write!(f, "#\\{}", c),
+            },
+            Datum::String(s) => write!(f, "{}", string_literal(s)),
+            Datum::Eof => write!(f, "eof"),
+            Datum::Empty => write!(f, "'()"),
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Operation {
     // Op0
     ReadByte,
@@ -61,54 +86,122 @@ pub enum Operation {
     VectorSetBang(Box<Expr>, Box<Expr>, Box<Expr>),
 }
 
-impl std::fmt::Display for Operation {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+impl Operation {
+    /// The name of the primitive, as written in Racket
+    pub fn name(&self) -> &'static str {
         match self {
-            // Op0
-            Operation::ReadByte => write!(f, "read-byte"),
-            Operation::PeekByte => write!(f, "peek-byte"),
-            Operation::Void => write!(f, "void"),
-
-            // Op1
-            Operation::Add1(e) => write!(f, "add1 {}", e),
-            Operation::Sub1(e) => write!(f, "sub1 {}", e),
-            Operation::ZeroHuh(e) => write!(f, "zero? {}", e),
-            Operation::CharHuh(e) => write!(f, "char? {}", e),
-            Operation::IntegerToChar(e) => write!(f, "integer->char {}", e),
-            Operation::CharToInteger(e) => write!(f, "char->integer {}", e),
-            Operation::WriteByte(e) => write!(f, "write-byte {}", e),
-            Operation::EofObjectHuh(e) => write!(f, " {}", e),
-            Operation::Box(e) => write!(f, "box {}", e),
-            Operation::Car(e) => write!(f, "car {}", e),
-            Operation::Cdr(e) => write!(f, "cdr {}", e),
-            Operation::Unbox(e) => write!(f, "unbox {}", e),
-            Operation::EmptyHuh(e) => write!(f, "empty? {}", e),
-            Operation::ConsHuh(e) => write!(f, "cons? {}", e),
-            Operation::BoxHuh(e) => write!(f, "box? {}", e),
-            Operation::VectorHuh(e) => write!(f, "vector? {}", e),
-            Operation::VectorLength(e) => write!(f, "vector-length {}", e),
-            Operation::StringHuh(e) => write!(f, "string? {}", e),
-            Operation::StringLength(e) => write!(f, "string-length {}", e),
-
-            // Op2
-            Operation::Plus(e1, e2) => write!(f, "+ {} {}", e1, e2),
-            Operation::Sub(e1, e2) => write!(f, "- {} {}", e1, e2),
-            Operation::Less(e1, e2) => write!(f, "< {} {}", e1, e2),
-            Operation::Equal(e1, e2) => write!(f, "equal? {} {}", e1, e2),
-            Operation::EqHuh(e1, e2) => write!(f, "eq? {} {}", e1, e2),
-            Operation::Cons(e1, e2) => write!(f, "cons {} {}", e1, e2),
-            Operation::MakeVector(e1, e2) => write!(f, "make-vector {} {}", e1, e2),
-            Operation::VectorRef(e1, e2) => write!(f, "vector-ref {} {}", e1, e2),
-            Operation::MakeString(e1, e2) => write!(f, "make-string {} {}", e1, e2),
-            Operation::StringRef(e1, e2) => write!(f, "string-ref {} {}", e1, e2),
-
-            // Op3
-            Operation::VectorSetBang(e1, e2, e3) => write!(f, "vector-set! {} {} {}", e1, e2, e3),
+            Operation::ReadByte => "read-byte",
+            Operation::PeekByte => "peek-byte",
+            Operation::Void => "void",
+            Operation::Add1(_) => "add1",
+            Operation::Sub1(_) => "sub1",
+            Operation::ZeroHuh(_) => "zero?",
+            Operation::CharHuh(_) => "char?",
+            Operation::IntegerToChar(_) => "integer->char",
+            Operation::CharToInteger(_) => "char->integer",
+            Operation::WriteByte(_) => "write-byte",
+            Operation::EofObjectHuh(_) => "eof-object?",
+            Operation::Box(_) => "box",
+            Operation::Car(_) => "car",
+            Operation::Cdr(_) => "cdr",
+            Operation::Unbox(_) => "unbox",
+            Operation::EmptyHuh(_) => "empty?",
+            Operation::ConsHuh(_) => "cons?",
+            Operation::BoxHuh(_) => "box?",
+            Operation::VectorHuh(_) => "vector?",
+            Operation::VectorLength(_) => "vector-length",
+            Operation::StringHuh(_) => "string?",
+            Operation::StringLength(_) => "string-length",
+            Operation::Plus(..) => "+",
+            Operation::Sub(..) => "-",
+            Operation::Less(..) => "<",
+            Operation::Equal(..) => "=",
+            Operation::EqHuh(..) => "eq?",
+            Operation::Cons(..) => "cons",
+            Operation::MakeVector(..) => "make-vector",
+            Operation::VectorRef(..) => "vector-ref",
+            Operation::MakeString(..) => "make-string",
+            Operation::StringRef(..) => "string-ref",
+            Operation::VectorSetBang(..) => "vector-set!",
+        }
+    }
+
+    /// The operands of the primitive, in order
+    pub fn operands(&self) -> Vec<&Expr> {
+        match self {
+            Operation::ReadByte | Operation::PeekByte | Operation::Void => vec![],
+            Operation::Add1(e)
+            | Operation::Sub1(e)
+            | Operation::ZeroHuh(e)
+            | Operation::CharHuh(e)
+            | Operation::IntegerToChar(e)
+            | Operation::CharToInteger(e)
+            | Operation::WriteByte(e)
+            | Operation::EofObjectHuh(e)
+            | Operation::Box(e)
+            | Operation::Car(e)
+            | Operation::Cdr(e)
+            | Operation::Unbox(e)
+            | Operation::EmptyHuh(e)
+            | Operation::ConsHuh(e)
+            | Operation::BoxHuh(e)
+            | Operation::VectorHuh(e)
+            | Operation::VectorLength(e)
+            | Operation::StringHuh(e)
+            | Operation::StringLength(e) => vec![e],
+            Operation::Plus(e1, e2)
+            | Operation::Sub(e1, e2)
+            | Operation::Less(e1, e2)
+            | Operation::Equal(e1, e2)
+            | Operation::EqHuh(e1, e2)
+            | Operation::Cons(e1, e2)
+            | Operation::MakeVector(e1, e2)
+            | Operation::VectorRef(e1, e2)
+            | Operation::MakeString(e1, e2)
+            | Operation::StringRef(e1, e2) => vec![e1, e2],
+            Operation::VectorSetBang(e1, e2, e3) => vec![e1, e2, e3],
+        }
+    }
+
+    /// The operands of the primitive, in order, for rewriting
+    pub fn operands_mut(&mut self) -> Vec<&mut Expr> {
+        match self {
+            Operation::ReadByte | Operation::PeekByte | Operation::Void => vec![],
+            Operation::Add1(e)
+            | Operation::Sub1(e)
+            | Operation::ZeroHuh(e)
+            | Operation::CharHuh(e)
+            | Operation::IntegerToChar(e)
+            | Operation::CharToInteger(e)
+            | Operation::WriteByte(e)
+            | Operation::EofObjectHuh(e)
+            | Operation::Box(e)
+            | Operation::Car(e)
+            | Operation::Cdr(e)
+            | Operation::Unbox(e)
+            | Operation::EmptyHuh(e)
+            | Operation::ConsHuh(e)
+            | Operation::BoxHuh(e)
+            | Operation::VectorHuh(e)
+            | Operation::VectorLength(e)
+            | Operation::StringHuh(e)
+            | Operation::StringLength(e) => vec![e],
+            Operation::Plus(e1, e2)
+            | Operation::Sub(e1, e2)
+            | Operation::Less(e1, e2)
+            | Operation::Equal(e1, e2)
+            | Operation::EqHuh(e1, e2)
+            | Operation::Cons(e1, e2)
+            | Operation::MakeVector(e1, e2)
+            | Operation::VectorRef(e1, e2)
+            | Operation::MakeString(e1, e2)
+            | Operation::StringRef(e1, e2) => vec![e1, e2],
+            Operation::VectorSetBang(e1, e2, e3) => vec![e1, e2, e3],
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Pattern {
     Var(Id),
     Literal(Datum),
@@ -117,19 +210,35 @@ pub enum Pattern {
     Conj(Box<Pattern>, Box<Pattern>),
 }
 
+impl Pattern {
+    pub fn to_source(&self, names: &Names) -> String {
+        match self {
+            Pattern::Var(id) => name(names, *id),
+            Pattern::Literal(d) => d.to_string(),
+            Pattern::Box(p) => format!("(box {})", p.to_source(names)),
+            Pattern::Cons(p1, p2) => {
+                format!("(cons {} {})", p1.to_source(names), p2.to_source(names))
+            }
+            Pattern::Conj(p1, p2) => {
+                format!("(and {} {})", p1.to_source(names), p2.to_source(names))
+            }
+        }
+    }
+}
+
 impl std::fmt::Display for Pattern {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        // TODO: uh ??? im lazy
-        write!(f, "({:?})", self)
+        write!(f, "{}", self.to_source(&Names::new()))
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Expr {
     Literal(Datum),
     Op(Operation),
     If(Box<Expr>, Box<Expr>, Box<Expr>),
-    Begin(Box<Expr>, Box<Expr>),
+    /// Evaluates each expression in order, producing the value of the last
+    Begin(Vec<Expr>),
     Let(Id, Box<Expr>, Box<Expr>),
     Var(Id),
     App(Box<Expr>, Vec<Expr>),
@@ -141,57 +250,291 @@ pub enum Expr {
     Unknown,
 }
 
-impl std::fmt::Display for Expr {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+/// `(head args...)`, with the arguments aligned under the first one if they
+/// don't fit on one line
+fn call(head: Doc, args: Vec<Doc>) -> Doc {
+    if args.is_empty() {
+        return concat([text("("), head, text(")")]);
+    }
+    group(concat([
+        text("("),
+        head,
+        text(" "),
+        align(lines(args)),
+        text(")"),
+    ]))
+}
+
+/// `(header body...)`, with the body indented by two if it doesn't fit on
+/// one line, as for `begin`, `let` and `define`
+fn block(header: Doc, body: Vec<Doc>) -> Doc {
+    align(group(concat([
+        text("("),
+        header,
+        nest(2, concat([line(), lines(body)])),
+        text(")"),
+    ])))
+}
+
+fn vars(names: &Names, ids: &[Id]) -> String {
+    ids.iter()
+        .map(|&id| name(names, id))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+impl Expr {
+    pub fn to_doc(&self, names: &Names) -> Doc {
+        match self {
+            Expr::Literal(d) => text(d.to_string()),
+            Expr::Op(o) => call(
+                text(o.name()),
+                o.operands().into_iter().map(|e| e.to_doc(names)).collect(),
+            ),
+            Expr::If(e1, e2, e3) => call(
+                text("if"),
+                vec![e1.to_doc(names), e2.to_doc(names), e3.to_doc(names)],
+            ),
+            Expr::Begin(es) => block(text("begin"), es.iter().map(|e| e.to_doc(names)).collect()),
+            Expr::Let(id, e1, e2) => block(
+                concat([
+                    text(format!("let ([{} ", name(names, *id))),
+                    align(e1.to_doc(names)),
+                    text("])"),
+                ]),
+                vec![e2.to_doc(names)],
+            ),
+            Expr::Var(id) => text(name(names, *id)),
+            Expr::App(proc, es) => call(
+                proc.to_doc(names),
+                es.iter().map(|e| e.to_doc(names)).collect(),
+            ),
+            Expr::Match(e, patterns, bodies) => block(
+                concat([text("match "), align(e.to_doc(names))]),
+                patterns
+                    .iter()
+                    .zip(bodies)
+                    .map(|(p, body)| {
+                        align(group(concat([
+                            text(format!("[{}", p.to_source(names))),
+                            nest(1, concat([line(), body.to_doc(names)])),
+                            text("]"),
+                        ])))
+                    })
+                    .collect(),
+            ),
+            Expr::Lam(_, params, body) => block(
+                text(format!("lambda ({})", vars(names, params))),
+                vec![body.to_doc(names)],
+            ),
+            Expr::Unknown => text("(Unknown)"),
+        }
+    }
+}
+
+impl Expr {
+    /// The immediate subexpressions, in evaluation order where there is one
+    pub fn children(&self) -> Vec<&Expr> {
+        match self {
+            Expr::Literal(_) | Expr::Var(_) | Expr::Unknown => vec![],
+            Expr::Op(o) => o.operands(),
+            Expr::If(e1, e2, e3) => vec![e1, e2, e3],
+            Expr::Begin(es) => es.iter().collect(),
+            Expr::Let(_, e1, e2) => vec![e1, e2],
+            Expr::App(e, es) => std::iter::once(&**e).chain(es).collect(),
+            Expr::Match(e, _, es) => std::iter::once(&**e).chain(es).collect(),
+            Expr::Lam(_, _, e) => vec![e],
+        }
+    }
+
+    /// Whether evaluating the expression has no effect besides producing its value
+    pub fn is_pure_literal(&self) -> bool {
+        matches!(self, Expr::Literal(_) | Expr::Op(Operation::Void))
+    }
+
+    /// The number of `Unknown`s in the expression, i.e. parts the decompiler
+    /// couldn't make sense of
+    pub fn unknowns(&self) -> usize {
         match self {
-            Expr::Literal(d) => write!(f, "{}", d),
-            Expr::Op(o) => write!(f, "({})", o),
-            Expr::If(e1, e2, e3) => write!(f, "(if {} {} {})", e1, e2, e3),
-            Expr::Begin(e1, e2) => write!(f, "(begin\n  {}\n  {})", e1, e2),
-            Expr::Let(id, e1, e2) => write!(f, "(let ([var{} {}])\n  {})", id, e1, e2),
-            Expr::Var(id) => write!(f, "var{}", id),
-            Expr::App(proc, es) => {
-                write!(f, "({}", proc)?;
-                for e in es {
-                    write!(f, " {}", e)?;
+            Expr::Unknown => 1,
+            _ => self.children().into_iter().map(Expr::unknowns).sum(),
+        }
+    }
+
+    /// Rewrites `begin`s into canonical form: nested `begin`s are spliced into
+    /// their parent, literals in effect position (which do nothing) are dropped,
+    /// and a `begin` of a single expression is replaced by the expression
+    pub fn normalize(&mut self) {
+        match self {
+            Expr::Literal(_) | Expr::Var(_) | Expr::Unknown => {}
+            Expr::Op(o) => o.operands_mut().into_iter().for_each(Expr::normalize),
+            Expr::If(e1, e2, e3) => {
+                e1.normalize();
+                e2.normalize();
+                e3.normalize();
+            }
+            Expr::Begin(es) => {
+                let mut body = Vec::new();
+                for mut e in std::mem::take(es) {
+                    e.normalize();
+                    match e {
+                        Expr::Begin(inner) => body.extend(inner),
+                        e => body.push(e),
+                    }
                 }
-                write!(f, ")")
+                let last = body.pop();
+                body.retain(|e| !e.is_pure_literal());
+                body.extend(last);
+                *self = match body.len() {
+                    1 => body.pop().unwrap(),
+                    _ => Expr::Begin(body),
+                };
+            }
+            Expr::Let(_, e1, e2) => {
+                e1.normalize();
+                e2.normalize();
             }
-            // TODO: add match and lam if we get to those
-            _ => write!(f, "({:?})", self),
+            Expr::App(e, es) => {
+                e.normalize();
+                es.iter_mut().for_each(Expr::normalize);
+            }
+            Expr::Match(e, _, es) => {
+                e.normalize();
+                es.iter_mut().for_each(Expr::normalize);
+            }
+            Expr::Lam(_, _, e) => e.normalize(),
         }
     }
 }
 
-#[derive(Debug)]
+/// Pretty-prints the expression, in lines of the formatter's width (as in
+/// `{:40}`) or `DEFAULT_WIDTH`
+impl std::fmt::Display for Expr {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let width = f.width().unwrap_or(DEFAULT_WIDTH);
+        write!(f, "{}", self.to_doc(&Names::new()).render(width))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Defn(pub Id, pub Vec<Id>, pub Box<Expr>);
 
+impl Defn {
+    pub fn to_doc(&self, names: &Names) -> Doc {
+        let defn = names
+            .get(&self.0)
+            .cloned()
+            .unwrap_or_else(|| format!("defn{}", self.0));
+        let header = if self.1.is_empty() {
+            format!("define ({})", defn)
+        } else {
+            format!("define ({} {})", defn, vars(names, &self.1))
+        };
+        block(text(header), vec![self.2.to_doc(names)])
+    }
+}
+
 impl std::fmt::Display for Defn {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        if self.1.len() > 0 {
-            write!(f, "(define (defn{}", self.0)?;
-            for var in &self.1 {
-                write!(f, " var{}", var)?;
-            }
-            write!(f, ")\n  {})", self.2)
-        } else {
-            write!(f, "(define defn{} {})", self.0, self.2)
-        }
+        let width = f.width().unwrap_or(DEFAULT_WIDTH);
+        write!(f, "{}", self.to_doc(&Names::new()).render(width))
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Program {
     pub defines: Vec<Defn>,
     pub expr: Box<Expr>,
+    pub names: Names,
 }
 
-impl std::fmt::Display for Program {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "#lang racket\n")?;
+impl Program {
+    /// Normalizes every expression in the program, as `Expr::normalize` does
+    pub fn normalize(&mut self) {
+        for Defn(_, _, body) in &mut self.defines {
+            body.normalize();
+        }
+        self.expr.normalize();
+    }
+
+    /// The number of `Unknown`s anywhere in the program
+    pub fn unknowns(&self) -> usize {
+        let defines: usize = self
+            .defines
+            .iter()
+            .map(|Defn(_, _, body)| body.unknowns())
+            .sum();
+        defines + self.expr.unknowns()
+    }
+
+    /// `names`, with unnamed definitions named `defn<id>` (so that they print
+    /// the same where they're used as where they're defined)
+    pub fn display_names(&self) -> Names {
+        let mut names = self.names.clone();
+        for Defn(id, _, _) in &self.defines {
+            names.entry(*id).or_insert_with(|| format!("defn{}", id));
+        }
+        names
+    }
+
+    /// The program's source, in lines of at most `width` columns where possible
+    pub fn pretty(&self, width: usize) -> String {
+        let names = self.display_names();
+        let mut out = String::from("#lang racket\n");
         for defn in &self.defines {
-            write!(f, "{}\n", defn)?;
+            out += &defn.to_doc(&names).render(width);
+            out.push('\n');
         }
-        write!(f, "{}", self.expr)
+        out + &self.expr.to_doc(&names).render(width)
+    }
+}
+
+impl std::fmt::Display for Program {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.pretty(f.width().unwrap_or(DEFAULT_WIDTH)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::parse_program;
+
+    #[test]
+    fn normalize_flattens_begins() {
+        let mut program = parse_program(
+            "(begin (begin (read-byte) 1) (add1 (begin 2 (void) 3)) (begin #t (begin (peek-byte))))",
+        )
+        .unwrap();
+        program.normalize();
+        assert_eq!(
+            program.expr.to_string(),
+            "(begin (read-byte) (add1 3) (peek-byte))"
+        );
+    }
+
+    #[test]
+    fn pretty_prints_to_width() {
+        let program = parse_program(
+            "(define (f x y)
+               (match (cons x y)
+                 [(cons a (box b)) (let ([z (+ a b)]) (begin (write-byte z) (f z b)))]
+                 [_ ((lambda (q) (+ q q)) 1)]))
+             (f 1 2)",
+        )
+        .unwrap();
+        assert_eq!(
+            program.pretty(40),
+            "#lang racket
+(define (f x y)
+  (match (cons x y)
+    [(cons a (box b))
+     (let ([z (+ a b)])
+       (begin (write-byte z) (f z b)))]
+    [_ ((lambda (q) (+ q q)) 1)]))
+(f 1 2)"
+        );
+        assert_eq!(format!("{:20}", program.expr), program.expr.to_string());
+        let body = &program.defines[0].2;
+        assert!(format!("{:12}", body).lines().count() > body.to_string().lines().count());
     }
 }
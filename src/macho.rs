@@ -0,0 +1,308 @@
+use anyhow::{Context, Result, bail, ensure};
+
+use crate::a86::Address;
+
+/*
+  Loading 64-bit x86 Mach-O objects, as built on macOS before linking. Linked
+  executables aren't supported: they call the runtime through `__stubs`,
+  which would need the indirect symbol table to name. Only what the decompiler needs is read: the
+  `__TEXT,__text` section and the symbol table. Symbols get the leading `_`
+  that C names get on macOS stripped, so `_entry` is `entry` as on Linux.
+
+  In objects, undefined symbols (the runtime's functions) are given
+  addresses after the sections, and the relocations of `__text` are applied
+  against them, as for ELF objects.
+*/
+
+const MH_MAGIC_64: u32 = 0xfeedfacf;
+const MH_OBJECT: u32 = 0x1;
+const CPU_TYPE_X86_64: u32 = 0x0100_0007;
+const LC_SEGMENT_64: u32 = 0x19;
+const LC_SYMTAB: u32 = 0x2;
+
+const N_STAB: u8 = 0xe0;
+const N_TYPE: u8 = 0x0e;
+const N_UNDF: u8 = 0x0;
+const N_EXT: u8 = 0x01;
+
+const X86_64_RELOC_UNSIGNED: u32 = 0;
+const X86_64_RELOC_SIGNED: u32 = 1;
+const X86_64_RELOC_BRANCH: u32 = 2;
+// Signed relocations for operands followed by an immediate of 1, 2 or 4 bytes.
+// The field holds the addend less those bytes, so they work out like SIGNED.
+const X86_64_RELOC_SIGNED_1: u32 = 6;
+const X86_64_RELOC_SIGNED_2: u32 = 7;
+const X86_64_RELOC_SIGNED_4: u32 = 8;
+
+/// Whether `bytes` look like a 64-bit Mach-O file
+pub fn is_macho(bytes: &[u8]) -> bool {
+    bytes.get(..4) == Some(&MH_MAGIC_64.to_le_bytes())
+}
+
+/// The parts of a Mach-O file the decompiler needs
+#[derive(Debug, Clone, PartialEq)]
+pub struct MachO {
+    /// The contents of `__text`, with relocations applied
+    pub text: Vec<u8>,
+    pub text_address: Address,
+    /// Every named symbol that isn't debugging information
+    pub symbols: Vec<(String, Address)>,
+}
+
+struct Section {
+    segment: String,
+    name: String,
+    address: Address,
+    size: u64,
+    offset: u32,
+    relocations: u32,
+    relocation_count: u32,
+}
+
+/// Little-endian reads at offsets into the file
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn bytes(&self, offset: usize, length: usize) -> Result<&[u8]> {
+        self.0
+            .get(offset..offset + length)
+            .context("Mach-O file is truncated")
+    }
+    fn u8(&self, offset: usize) -> Result<u8> {
+        Ok(self.bytes(offset, 1)?[0])
+    }
+    fn u32(&self, offset: usize) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(offset, 4)?.try_into()?))
+    }
+    fn u64(&self, offset: usize) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(offset, 8)?.try_into()?))
+    }
+    /// A NUL-padded name of at most `length` bytes
+    fn name(&self, offset: usize, length: usize) -> Result<String> {
+        let bytes = self.bytes(offset, length)?;
+        let end = bytes.iter().position(|&b| b == 0).unwrap_or(length);
+        Ok(String::from_utf8_lossy(&bytes[..end]).into_owned())
+    }
+    /// A NUL-terminated string
+    fn string(&self, offset: usize) -> Result<String> {
+        let rest = self.0.get(offset..).context("Mach-O file is truncated")?;
+        self.name(offset, rest.len())
+    }
+}
+
+pub fn parse(bytes: &[u8]) -> Result<MachO> {
+    let file = Reader(bytes);
+    ensure!(file.u32(0)? == MH_MAGIC_64, "not a 64-bit Mach-O file");
+    ensure!(
+        file.u32(4)? == CPU_TYPE_X86_64,
+        "Mach-O file is not for x86-64"
+    );
+    ensure!(
+        file.u32(12)? == MH_OBJECT,
+        "only Mach-O objects are supported, not linked executables"
+    );
+    let command_count = file.u32(16)?;
+
+    // Read the sections and where the symbol table is from the load commands
+    let mut sections = Vec::new();
+    let mut symbol_table = None;
+    let mut offset = 32;
+    for _ in 0..command_count {
+        let (command, size) = (file.u32(offset)?, file.u32(offset + 4)? as usize);
+        match command {
+            LC_SEGMENT_64 => {
+                let section_count = file.u32(offset + 64)?;
+                for i in 0..section_count as usize {
+                    let section = offset + 72 + 80 * i;
+                    sections.push(Section {
+                        name: file.name(section, 16)?,
+                        segment: file.name(section + 16, 16)?,
+                        address: file.u64(section + 32)?,
+                        size: file.u64(section + 40)?,
+                        offset: file.u32(section + 48)?,
+                        relocations: file.u32(section + 56)?,
+                        relocation_count: file.u32(section + 60)?,
+                    });
+                }
+            }
+            LC_SYMTAB => {
+                symbol_table = Some((
+                    file.u32(offset + 8)? as usize,
+                    file.u32(offset + 12)? as usize,
+                    file.u32(offset + 16)? as usize,
+                ))
+            }
+            _ => {}
+        }
+        ensure!(size > 0, "Mach-O file has an empty load command");
+        offset += size;
+    }
+
+    let text = sections
+        .iter()
+        .find(|s| s.segment == "__TEXT" && s.name == "__text")
+        .context("Mach-O file did not have a __text section")?;
+    let mut next_external = sections
+        .iter()
+        .map(|s| s.address + s.size)
+        .max()
+        .unwrap_or(0)
+        .next_multiple_of(16);
+
+    // Read the symbols, giving undefined ones addresses of their own
+    let (symbols_offset, symbol_count, strings_offset) =
+        symbol_table.context("Mach-O file did not have a symbol table")?;
+    let mut symbols = Vec::new();
+    // The address of each symbol, by index
+    let mut symbol_addresses = Vec::new();
+    for i in 0..symbol_count {
+        let entry = symbols_offset + 16 * i;
+        let name = file.string(strings_offset + file.u32(entry)? as usize)?;
+        let kind = file.u8(entry + 4)?;
+        let address = match kind & N_TYPE {
+            N_UNDF if kind & N_EXT != 0 => {
+                next_external += 16;
+                next_external - 16
+            }
+            _ => file.u64(entry + 8)?,
+        };
+        symbol_addresses.push(address);
+        if kind & N_STAB == 0 && !name.is_empty() {
+            let name = name.strip_prefix('_').unwrap_or(&name).to_owned();
+            symbols.push((name, address));
+        }
+    }
+
+    let mut code = file
+        .bytes(text.offset as usize, text.size as usize)?
+        .to_vec();
+    for i in 0..text.relocation_count as usize {
+        let entry = text.relocations as usize + 8 * i;
+        let (place, info) = (file.u32(entry)?, file.u32(entry + 4)?);
+        ensure!(
+            place & 0x8000_0000 == 0,
+            "scattered relocations are not implemented"
+        );
+        let (symbol, pc_relative, length, external, kind) = (
+            info & 0x00ff_ffff,
+            info >> 24 & 1 == 1,
+            info >> 25 & 3,
+            info >> 27 & 1 == 1,
+            info >> 28,
+        );
+        if !external {
+            // Relocations against sections only matter once the sections move
+            continue;
+        }
+        let target = *symbol_addresses
+            .get(symbol as usize)
+            .context("relocation refers to a missing symbol")?;
+        let offset = place as usize;
+        let width = 1 << length;
+        let field = code
+            .get_mut(offset..offset + width)
+            .context("relocation outside of __text")?;
+        // The addend is whatever's in the field already
+        let addend = match width {
+            4 => i32::from_le_bytes(field.try_into()?) as i64,
+            8 => i64::from_le_bytes(field.try_into()?),
+            _ => bail!("relocation of {} bytes not implemented", width),
+        };
+        let value = match kind {
+            X86_64_RELOC_BRANCH
+            | X86_64_RELOC_SIGNED
+            | X86_64_RELOC_SIGNED_1
+            | X86_64_RELOC_SIGNED_2
+            | X86_64_RELOC_SIGNED_4
+                if pc_relative =>
+            {
+                let next = text.address + place as u64 + 4;
+                target.wrapping_add_signed(addend).wrapping_sub(next)
+            }
+            X86_64_RELOC_UNSIGNED if !pc_relative => target.wrapping_add_signed(addend),
+            kind => bail!("relocation type {} not implemented", kind),
+        };
+        field.copy_from_slice(&value.to_le_bytes()[..width]);
+    }
+
+    Ok(MachO {
+        text: code,
+        text_address: text.address,
+        symbols,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{a86::Program, decompiler};
+
+    const FIXTURE: &[u8] = include_bytes!("../test-programs/read-byte-macos.o");
+
+    #[test]
+    fn reads_object_from_macos() {
+        assert!(is_macho(FIXTURE));
+        let macho = parse(FIXTURE).unwrap();
+        let address = |name: &str| {
+            macho
+                .symbols
+                .iter()
+                .find(|(symbol, _)| symbol == name)
+                .map(|&(_, address)| address)
+        };
+        assert_eq!(address("entry"), Some(0));
+        assert!(address("_entry").is_none());
+        // Undefined symbols go after __text
+        let read_byte = address("read_byte").unwrap();
+        assert!(read_byte >= macho.text_address + macho.text.len() as u64);
+
+        let program = Program::from_bytes(FIXTURE).unwrap();
+        assert!(
+            program
+                .instructions()
+                .contains(&crate::a86::Instruction::Call(read_byte))
+        );
+        assert_eq!(
+            decompiler::parse(&program).unwrap().to_string(),
+            "#lang racket\n(add1 (read-byte))"
+        );
+    }
+
+    #[test]
+    fn relocates_operands_followed_by_immediates() {
+        let fixture = include_bytes!("../test-programs/rip-relative-macos.o");
+        let macho = parse(fixture).unwrap();
+        let data = macho
+            .symbols
+            .iter()
+            .find(|(symbol, _)| symbol == "data")
+            .map(|&(_, address)| address)
+            .unwrap();
+
+        let program = Program::from_bytes(fixture).unwrap();
+        let targets: Vec<_> = program
+            .instructions()
+            .iter()
+            .flat_map(crate::a86::Instruction::operands)
+            .filter_map(|arg| match arg {
+                crate::a86::Arg::Memory(memory) => memory.target(),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(targets, [data, data, data, data + 8, data]);
+    }
+
+    #[test]
+    fn rejects_executables() {
+        let mut executable = FIXTURE.to_vec();
+        executable[12] = 2;
+        let error = parse(&executable).unwrap_err();
+        assert!(error.to_string().contains("executables"), "{}", error);
+    }
+
+    #[test]
+    fn rejects_truncated_files() {
+        let error = parse(&FIXTURE[..100]).unwrap_err();
+        assert!(error.to_string().contains("truncated"), "{}", error);
+    }
+}
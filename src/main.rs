@@ -1,32 +1,337 @@
-mod a86;
-mod decompiler;
-mod loot;
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process::ExitCode,
+    thread,
+};
 
-use std::path::PathBuf;
+use anyhow::{Context, Result, anyhow};
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum, error::ErrorKind};
+use serde_json::json;
 
-use anyhow::Result;
-use clap::Parser;
+use compiler_deconstruction::{
+    Decompilation, DecompileOptions, Diagnostic, a86::Program, alpha, batch, cfg, disasm, dot,
+    json, parser, pretty, provenance, sexp,
+};
 
-use a86::Program;
-use decompiler::parse;
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Format {
+    /// Plain text; for `decompile`, Racket source
+    Text,
+    /// JSON (see json.rs)
+    Json,
+    /// The disassembly, annotated with the expressions decompiled from it
+    Listing,
+    /// Graphviz: for `decompile` the AST, for `cfg` the control-flow graph
+    Dot,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Encoding {
+    Utf8,
+    /// Non-ASCII characters are escaped
+    Ascii,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Decompile a program
+    Decompile {
+        program: PathBuf,
+
+        /// Extra sections to include in JSON output
+        #[arg(long, value_enum, value_delimiter = ',')]
+        include: Vec<json::Section>,
+
+        /// Line width to lay the decompiled program out in
+        #[arg(long, default_value_t = pretty::DEFAULT_WIDTH)]
+        width: usize,
+    },
+    /// Print the decoded instructions the decompiler works from
+    Disasm {
+        program: PathBuf,
+
+        #[arg(long, value_enum, default_value_t = disasm::Syntax::Both)]
+        syntax: disasm::Syntax,
+    },
+    /// Print a program's symbols, ordered by address
+    Symbols { program: PathBuf },
+    /// Print the basic blocks of a program's control-flow graph
+    Cfg { program: PathBuf },
+    /// Check that a program decompiles to something alpha-equivalent to its source
+    Verify { program: PathBuf, source: PathBuf },
+    /// Decompile every `.run` under a directory, writing `.decompiled.rkt`
+    /// files next to them, and summarize how each went
+    Batch {
+        dir: PathBuf,
+
+        /// How many programs to decompile at once (by default, one per CPU)
+        #[arg(long, short)]
+        jobs: Option<usize>,
+
+        /// Line width to lay the decompiled programs out in
+        #[arg(long, default_value_t = pretty::DEFAULT_WIDTH)]
+        width: usize,
+    },
+}
 
 #[derive(Parser)]
 struct Args {
-    program: PathBuf,
+    #[command(subcommand)]
+    command: Command,
+
+    /// Write the output to this file instead of stdout
+    #[arg(long, short, global = true)]
+    output: Option<PathBuf>,
+
+    #[arg(long, value_enum, global = true, default_value_t = Format::Text)]
+    format: Format,
+
+    #[arg(long, value_enum, global = true, default_value_t = Encoding::Utf8)]
+    encoding: Encoding,
+
+    /// Don't print warnings and other notes to stderr
+    #[arg(long, short, global = true)]
+    quiet: bool,
+}
+
+/// Why a command failed, which decides the exit status. Scripts may rely on
+/// these statuses; 2 is a bad command line.
+enum Failure {
+    /// The program couldn't be decompiled (1)
+    Decompile(anyhow::Error),
+    /// An input couldn't be read or parsed (3)
+    Input(anyhow::Error),
+    /// The decompiled program isn't equivalent to the source (4)
+    Mismatch(anyhow::Error),
+    /// The output couldn't be written (5)
+    Output(anyhow::Error),
 }
 
-fn main() -> Result<()> {
+impl Failure {
+    fn exit_code(&self) -> u8 {
+        match self {
+            Failure::Decompile(_) => 1,
+            Failure::Input(_) => 3,
+            Failure::Mismatch(_) => 4,
+            Failure::Output(_) => 5,
+        }
+    }
+
+    fn error(&self) -> &anyhow::Error {
+        match self {
+            Failure::Decompile(e)
+            | Failure::Input(e)
+            | Failure::Mismatch(e)
+            | Failure::Output(e) => e,
+        }
+    }
+}
+
+fn load(path: &Path) -> Result<Program, Failure> {
+    Program::from_file(path)
+        .with_context(|| format!("failed to load {}", path.display()))
+        .map_err(Failure::Input)
+}
+
+fn decompile(binary: &Program) -> Result<Decompilation, Failure> {
+    compiler_deconstruction::decompile(binary, &DecompileOptions::default())
+        .map_err(Failure::Decompile)
+}
+
+/// Exits with a usage error if `format` isn't among the `supported` ones
+fn require_format(format: Format, supported: &[Format]) {
+    if !supported.contains(&format) {
+        let name = format.to_possible_value().unwrap();
+        Args::command()
+            .error(
+                ErrorKind::InvalidValue,
+                format!(
+                    "--format {} isn't supported by this command",
+                    name.get_name()
+                ),
+            )
+            .exit();
+    }
+}
+
+fn run(args: &Args) -> Result<String, Failure> {
+    let json = |value: serde_json::Value| serde_json::to_string_pretty(&value).unwrap() + "\n";
+
+    Ok(match &args.command {
+        Command::Decompile {
+            program,
+            include,
+            width,
+        } => {
+            require_format(
+                args.format,
+                &[Format::Text, Format::Json, Format::Listing, Format::Dot],
+            );
+            let binary = load(program)?;
+            let Decompilation {
+                program: loot_program,
+                origins,
+                diagnostics,
+            } = decompile(&binary)?;
+            if !args.quiet {
+                for diagnostic in diagnostics {
+                    eprintln!("{}: {}", program.display(), diagnostic);
+                }
+            }
+            match args.format {
+                Format::Text => loot_program.pretty(*width) + "\n",
+                Format::Json => json(json::export(&loot_program, &origins, &binary, include)),
+                Format::Listing => provenance::listing(&loot_program, &origins, &binary),
+                Format::Dot => dot::ast(&loot_program, &origins, &binary),
+            }
+        }
+        Command::Disasm { program, syntax } => {
+            require_format(args.format, &[Format::Text, Format::Json, Format::Listing]);
+            let binary = load(program)?;
+            match args.format {
+                Format::Json => json(json::instructions(&binary)),
+                Format::Listing => {
+                    let decompilation = decompile(&binary)?;
+                    provenance::listing(&decompilation.program, &decompilation.origins, &binary)
+                }
+                _ => disasm::disassemble(&binary, *syntax),
+            }
+        }
+        Command::Symbols { program } => {
+            require_format(args.format, &[Format::Text, Format::Json]);
+            let binary = load(program)?;
+            match args.format {
+                Format::Json => json(json::symbols(&binary)),
+                _ => binary
+                    .symbols()
+                    .into_iter()
+                    .map(|(name, address)| format!("{:#x}  {}\n", address, name))
+                    .collect(),
+            }
+        }
+        Command::Cfg { program } => {
+            require_format(args.format, &[Format::Text, Format::Json, Format::Dot]);
+            let binary = load(program)?;
+            match args.format {
+                Format::Json => json(json::cfg(&binary)),
+                Format::Dot => dot::cfg(&binary),
+                _ => cfg::Cfg::build(&binary)
+                    .blocks
+                    .iter()
+                    .enumerate()
+                    .map(|(id, block)| {
+                        let successors: Vec<String> =
+                            block.successors.iter().map(|s| s.to_string()).collect();
+                        let line = format!(
+                            "{}  {:#x}  {} instruction(s)  -> {}",
+                            id,
+                            binary.index_to_address(block.instructions.start).unwrap(),
+                            block.instructions.len(),
+                            successors.join(", ")
+                        );
+                        line.trim_end().to_owned() + "\n"
+                    })
+                    .collect(),
+            }
+        }
+        Command::Batch { dir, jobs, width } => {
+            require_format(args.format, &[Format::Text, Format::Json]);
+            let programs = batch::find_programs(dir).map_err(Failure::Input)?;
+            let jobs =
+                jobs.unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get()));
+            let outcomes = batch::run(&programs, jobs, &DecompileOptions::default(), |program| {
+                let source = program.pretty(*width);
+                match args.encoding {
+                    Encoding::Utf8 => source,
+                    Encoding::Ascii => sexp::to_ascii(&source),
+                }
+            });
+            // Failures are part of the report rather than errors
+            match args.format {
+                Format::Json => json(batch::to_json(&outcomes)),
+                _ => batch::report(&outcomes),
+            }
+        }
+        Command::Verify { program, source } => {
+            require_format(args.format, &[Format::Text, Format::Json]);
+            let text = fs::read_to_string(source)
+                .with_context(|| format!("failed to read {}", source.display()))
+                .map_err(Failure::Input)?;
+            let mut expected = parser::parse_program(&text)
+                .with_context(|| format!("failed to parse {}", source.display()))
+                .map_err(Failure::Input)?;
+            expected.normalize();
+            let decompilation = decompile(&load(program)?)?;
+
+            let difference = alpha::diff_programs(&expected, &decompilation.program);
+            if args.format == Format::Json {
+                // The verdict is the output, so it isn't an error as well
+                let difference = difference.map(|d| d.to_string());
+                return Ok(json(json!({
+                    "equivalent": difference.is_none(),
+                    "difference": difference,
+                })));
+            }
+            if let Some(difference) = difference {
+                return Err(Failure::Mismatch(anyhow!(
+                    "decompiled program differs from {} {}",
+                    source.display(),
+                    difference
+                )));
+            }
+            if !args.quiet {
+                eprintln!("Equivalent to {}", source.display());
+            }
+            String::new()
+        }
+    })
+}
+
+fn main() -> ExitCode {
     let args = Args::parse();
 
-    // Construct an A86 program from the input program
-    let a86_program = Program::from_elf_file(&args.program)?;
-    //println!("Program: {:#x?}", program);
+    let output = run(&args).and_then(|mut output| {
+        if args.encoding == Encoding::Ascii {
+            output = match args.format {
+                Format::Json => json::to_ascii(&output),
+                _ => sexp::to_ascii(&output),
+            };
+        }
+        let written = match &args.output {
+            Some(path) => fs::write(path, output)
+                .with_context(|| format!("failed to write {}", path.display())),
+            None => io::stdout()
+                .write_all(output.as_bytes())
+                .context("failed to write to stdout"),
+        };
+        written.map_err(Failure::Output)
+    });
+
+    match output {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(failure) => {
+            // Decompiler errors say they're errors already
+            match failure.error().downcast_ref::<Diagnostic>() {
+                Some(diagnostic) => eprintln!("{}", diagnostic),
+                None => eprintln!("error: {:#}", failure.error()),
+            }
+            ExitCode::from(failure.exit_code())
+        }
+    }
+}
 
-    // Decompile the program
-    let loot_program = parse(&a86_program)?;
-    println!("Decompiled Program:");
-    // println!("{:#x?}", loot_program);
-    println!("{}", loot_program);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    Ok(())
+    #[test]
+    fn cli_is_well_formed() {
+        Args::command().debug_assert();
+        let args =
+            Args::try_parse_from(["cd", "symbols", "a.run", "--format", "json", "-q"]).unwrap();
+        assert!(matches!(args.command, Command::Symbols { .. }));
+        assert!(args.format == Format::Json && args.quiet);
+    }
 }
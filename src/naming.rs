@@ -0,0 +1,345 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use crate::{
+    loot::{Datum, Defn, Expr, Id, Names, Pattern, Program as LootProgram},
+    parser::is_reserved,
+};
+
+/*
+  Recovering readable names for the decompiled program.
+
+  The course compiler labels the code of every definition and lambda with
+  `symbol->label`, which keeps the source name (with characters nasm can't
+  take replaced by `_`) between a `label_` prefix and a hash suffix, so those
+  can mostly be read back. Variables live on the stack and leave no trace, so
+  they're named after how they're used instead: `n` for numbers, `xs` for
+  lists, `acc` for accumulating parameters, and so on.
+
+  This works on any loot AST, given the labels of its definitions and
+  lambdas. The decompiler doesn't produce those yet (`parse_defines` is
+  still a stub), so it doesn't name its output.
+*/
+
+/// Recovers the source name from a label made by `symbol->label`, as in
+/// `label_fact_1f2e` for `fact`
+pub fn label_to_symbol(label: &str) -> Option<String> {
+    let (name, hash) = label.strip_prefix("label_")?.rsplit_once('_')?;
+    let is_hash = !hash.is_empty() && hash.chars().all(|c| c.is_ascii_hexdigit());
+    (is_hash && !name.is_empty()).then(|| name.to_owned())
+}
+
+/// How a variable is used, in order of how telling it is about the variable
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Role {
+    Accumulator,
+    List,
+    Procedure,
+    Number,
+    Char,
+    Box,
+    Vector,
+    String,
+}
+
+impl Role {
+    fn name(self) -> &'static str {
+        match self {
+            Role::Accumulator => "acc",
+            Role::List => "xs",
+            Role::Procedure => "f",
+            Role::Number => "n",
+            Role::Char => "c",
+            Role::Box => "b",
+            Role::Vector => "v",
+            Role::String => "s",
+        }
+    }
+}
+
+/// The role an operand of the primitive `op` plays
+fn operand_role(op: &str, index: usize) -> Option<Role> {
+    Some(match (op, index) {
+        ("add1" | "sub1" | "zero?" | "+" | "-" | "<" | "=" | "integer->char" | "write-byte", _) => {
+            Role::Number
+        }
+        ("char->integer", _) => Role::Char,
+        ("car" | "cdr" | "empty?" | "cons?", _) | ("cons", 1) => Role::List,
+        ("unbox", _) => Role::Box,
+        ("vector-length", _) | ("vector-ref" | "vector-set!", 0) => Role::Vector,
+        ("string-length", _) | ("string-ref", 0) => Role::String,
+        ("make-vector" | "make-string" | "vector-ref" | "vector-set!" | "string-ref", _) => {
+            Role::Number
+        }
+        _ => return None,
+    })
+}
+
+/// Every variable referenced in `expr` (bound there or not)
+fn free_vars(expr: &Expr, vars: &mut HashSet<Id>) {
+    match expr {
+        Expr::Var(id) => {
+            vars.insert(*id);
+        }
+        _ => expr.children().into_iter().for_each(|e| free_vars(e, vars)),
+    }
+}
+
+fn pattern_vars(pattern: &Pattern, ids: &mut Vec<Id>) {
+    match pattern {
+        Pattern::Var(id) => ids.push(*id),
+        Pattern::Literal(_) => {}
+        Pattern::Box(p) => pattern_vars(p, ids),
+        Pattern::Cons(p1, p2) | Pattern::Conj(p1, p2) => {
+            pattern_vars(p1, ids);
+            pattern_vars(p2, ids);
+        }
+    }
+}
+
+#[derive(Default)]
+struct Usage {
+    roles: HashMap<Id, BTreeSet<Role>>,
+    /// Variables referenced anywhere
+    used: HashSet<Id>,
+    /// Binders, in the order they appear
+    binders: Vec<Id>,
+    /// Variables bound to lambdas, with the lambda's `Id`
+    lambdas: HashMap<Id, Id>,
+    /// Parameters of definitions, by the definition's `Id`
+    params: HashMap<Id, Vec<Id>>,
+    /// Variables passed as arguments to definitions, with the parameter they're
+    /// passed as
+    arguments: Vec<(Id, Id)>,
+}
+
+impl Usage {
+    fn role(&mut self, expr: &Expr, role: Role) {
+        if let Expr::Var(id) = expr {
+            self.roles.entry(*id).or_default().insert(role);
+        }
+    }
+
+    fn expr(&mut self, expr: &Expr, current: Option<&Defn>) {
+        match expr {
+            Expr::Var(id) => {
+                self.used.insert(*id);
+            }
+            Expr::Op(o) => {
+                for (i, operand) in o.operands().into_iter().enumerate() {
+                    if let Some(role) = operand_role(o.name(), i) {
+                        self.role(operand, role);
+                    }
+                }
+            }
+            Expr::Let(id, e1, _) => {
+                self.binders.push(*id);
+                if let Expr::Lam(lambda, _, _) = e1.as_ref() {
+                    self.lambdas.insert(*id, *lambda);
+                }
+            }
+            Expr::App(f, args) => {
+                self.role(f, Role::Procedure);
+                if let Expr::Var(f) = f.as_ref()
+                    && let Some(params) = self.params.get(f)
+                {
+                    for (&param, arg) in params.iter().zip(args) {
+                        if let Expr::Var(arg) = arg {
+                            self.arguments.push((*arg, param));
+                        }
+                    }
+                }
+                // A parameter that a recursive call passes on combined with
+                // something else is accumulating a result
+                if let (Expr::Var(f), Some(Defn(id, params, _))) = (f.as_ref(), current)
+                    && f == id
+                {
+                    for (&param, arg) in params.iter().zip(args) {
+                        let mut vars = HashSet::new();
+                        free_vars(arg, &mut vars);
+                        if vars.contains(&param) && vars.len() > 1 {
+                            self.roles
+                                .entry(param)
+                                .or_default()
+                                .insert(Role::Accumulator);
+                        }
+                    }
+                }
+            }
+            Expr::Match(e, patterns, _) => {
+                for pattern in patterns {
+                    match pattern {
+                        Pattern::Cons(..) | Pattern::Literal(Datum::Empty) => {
+                            self.role(e, Role::List)
+                        }
+                        Pattern::Box(_) => self.role(e, Role::Box),
+                        _ => {}
+                    }
+                    pattern_vars(pattern, &mut self.binders);
+                }
+            }
+            Expr::Lam(_, params, _) => self.binders.extend(params),
+            _ => {}
+        }
+        for child in expr.children() {
+            self.expr(child, current);
+        }
+    }
+}
+
+struct Namer {
+    names: Names,
+    taken: HashSet<String>,
+}
+
+impl Namer {
+    /// Names `id` after `base`, numbered to keep it distinct from every other name
+    fn name(&mut self, id: Id, base: &str) {
+        if self.names.contains_key(&id) {
+            return;
+        }
+        let mut name = base.to_owned();
+        let mut suffix = 1;
+        while is_reserved(&name) || self.taken.contains(&name) {
+            suffix += 1;
+            name = format!("{}{}", base, suffix);
+        }
+        self.taken.insert(name.clone());
+        self.names.insert(id, name);
+    }
+}
+
+/// Names every definition and variable in `program`. `symbols` are the labels
+/// of the code for definitions and lambdas, by `Id`.
+pub fn assign_names(program: &LootProgram, symbols: &HashMap<Id, HashSet<String>>) -> Names {
+    let label_name = |id: &Id| -> Option<String> {
+        let mut names: Vec<String> = symbols
+            .get(id)?
+            .iter()
+            .filter_map(|label| label_to_symbol(label))
+            .collect();
+        // Several labels may point at the same code; pick one deterministically
+        names.sort();
+        names.into_iter().next()
+    };
+
+    let mut usage = Usage {
+        params: program
+            .defines
+            .iter()
+            .map(|Defn(id, params, _)| (*id, params.clone()))
+            .collect(),
+        ..Usage::default()
+    };
+    for defn in &program.defines {
+        usage.binders.extend(&defn.1);
+        usage.expr(&defn.2, Some(defn));
+    }
+    usage.expr(&program.expr, None);
+    // Arguments are used however the parameters they're passed as are
+    for (arg, param) in std::mem::take(&mut usage.arguments) {
+        let roles = usage.roles.get(&param).cloned().unwrap_or_default();
+        let arg_roles = usage.roles.entry(arg).or_default();
+        arg_roles.extend(roles.into_iter().filter(|&role| role != Role::Accumulator));
+    }
+
+    let mut namer = Namer {
+        names: Names::new(),
+        taken: HashSet::new(),
+    };
+
+    // Names from labels first, since they're the ones from the source
+    for (i, Defn(id, _, _)) in program.defines.iter().enumerate() {
+        match label_name(id) {
+            Some(name) => namer.name(*id, &name),
+            None => namer.name(*id, if i == 0 { "f" } else { "g" }),
+        }
+    }
+    for (var, lambda) in &usage.lambdas {
+        // Labels of anonymous lambdas are made from gensyms like `lambda1234`
+        if let Some(name) = label_name(lambda)
+            && !name.starts_with("lambda")
+        {
+            namer.name(*var, &name);
+        }
+    }
+
+    let free: BTreeSet<Id> = usage
+        .used
+        .iter()
+        .filter(|id| !usage.binders.contains(id))
+        .copied()
+        .collect();
+    for id in usage.binders.iter().chain(&free) {
+        if !usage.used.contains(id) {
+            // Nothing refers to them, but Racket doesn't allow two parameters
+            // with the same name, `_` or not
+            if namer.taken.insert("_".to_owned()) {
+                namer.names.insert(*id, "_".to_owned());
+            } else {
+                namer.name(*id, "_");
+            }
+            continue;
+        }
+        let base = match usage.roles.get(id).and_then(|roles| roles.first()) {
+            Some(role) => role.name(),
+            None if usage.lambdas.contains_key(id) => "f",
+            None => "x",
+        };
+        namer.name(*id, base);
+    }
+
+    namer.names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_program;
+
+    #[test]
+    fn labels_decode_to_symbols() {
+        assert_eq!(label_to_symbol("label_fact_1f2e"), Some("fact".to_owned()));
+        assert_eq!(
+            label_to_symbol("label_list_to_vec_abc"),
+            Some("list_to_vec".to_owned())
+        );
+        assert_eq!(label_to_symbol("label_even?_3"), Some("even?".to_owned()));
+        assert_eq!(label_to_symbol("entry"), None);
+        assert_eq!(label_to_symbol("label_x_notahash"), None);
+    }
+
+    #[test]
+    fn names_follow_labels_and_usage() {
+        let mut program = parse_program(
+            "(define (fact n acc) (if (zero? n) acc (fact (sub1 n) (+ n acc))))
+             (define (len xs) (match xs ['() 0] [(cons x rest) (add1 (len rest))]))
+             (let ([x (fact 5 1)]) (let ([y (len '())]) (+ x y)))",
+        )
+        .unwrap();
+        let symbols = HashMap::from([
+            (
+                program.defines[0].0,
+                HashSet::from(["label_fact_9a".to_owned()]),
+            ),
+            (program.defines[1].0, HashSet::new()),
+        ]);
+        program.names = assign_names(&program, &symbols);
+        assert_eq!(
+            program.to_string(),
+            "#lang racket
+(define (fact n acc) (if (zero? n) acc (fact (sub1 n) (+ n acc))))
+(define (g xs) (match xs ['() 0] [(cons _ xs2) (add1 (g xs2))]))
+(let ([n2 (fact 5 1)]) (let ([n3 (g '())]) (+ n2 n3)))"
+        );
+    }
+
+    #[test]
+    fn unused_binders_are_distinct() {
+        let mut program = parse_program("(define (f x y) 1) (lambda (a b) (f a 2))").unwrap();
+        program.names = assign_names(&program, &HashMap::new());
+        assert_eq!(
+            program.to_string(),
+            "#lang racket\n(define (f _ _2) 1)\n(lambda (x _3) (f x 2))"
+        );
+    }
+}
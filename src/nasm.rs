@@ -0,0 +1,434 @@
+use std::collections::HashMap;
+
+use anyhow::{Context, Result, bail, ensure};
+
+use crate::{
+    a86::{Address, Arg, Instruction, Memory, Program, Register},
+    assembler::Assembler,
+};
+
+/*
+  Reading the NASM assembly the course compiler emits (`.s` files), so that
+  programs can be decompiled without assembling them. The subset understood
+  is what a86 prints: labels, `global`/`extern`/`section`/`default`
+  directives, `;` comments, and the instructions of `a86::Instruction` with
+  register, integer, label and memory operands. Data sections (`section
+  .data` or `.rodata`, where later languages keep string literals) are
+  rejected, as a `Program` only holds code.
+
+  The instructions are laid out as an assembler would, starting at
+  `TEXT_ADDRESS`, and each `extern` gets an address of its own from
+  `EXTERN_ADDRESS` on, so the result looks like a linked executable.
+*/
+
+pub const TEXT_ADDRESS: Address = 0x1000;
+pub const EXTERN_ADDRESS: Address = 0x1000_0000;
+
+fn register(name: &str) -> Option<Register> {
+    name.parse().ok()
+}
+
+fn integer(text: &str) -> Option<i64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let value = match digits.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok()? as i64,
+        None => digits.parse::<u64>().ok()? as i64,
+    };
+    Some(if negative {
+        value.wrapping_neg()
+    } else {
+        value
+    })
+}
+
+fn is_label(text: &str) -> bool {
+    let mut chars = text.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || "._?$".contains(c))
+        && chars.all(|c| c.is_ascii_alphanumeric() || "._?$#@~".contains(c))
+}
+
+struct Parser {
+    asm: Assembler,
+    externs: HashMap<String, Address>,
+}
+
+impl Parser {
+    /// The address of the label or extern `name`
+    fn target(&mut self, name: &str) -> Result<Address> {
+        if !is_label(name) {
+            bail!("expected a label, found `{}`", name);
+        }
+        Ok(match self.externs.get(name) {
+            Some(&address) => address,
+            None => self.asm.label(name),
+        })
+    }
+
+    /// The memory operand written `[text]`: `rel label`, or a sum of a base
+    /// register, an index register (maybe `* scale`) and a displacement. Its
+    /// size is left 0, for the operand's size keyword or instruction to set.
+    fn memory(&mut self, text: &str) -> Result<Memory> {
+        if let Some(label) = text.strip_prefix("rel ") {
+            return Ok(Memory {
+                size: 0,
+                ..Memory::rel(self.target(label.trim())?)
+            });
+        }
+        let mut memory = Memory {
+            base: None,
+            index: None,
+            scale: 1,
+            displacement: 0,
+            rip_relative: false,
+            size: 0,
+        };
+        // Each term with its sign
+        let terms = text.replace('-', "+-");
+        for term in terms.split('+').map(str::trim).filter(|t| !t.is_empty()) {
+            let invalid = || format!("invalid term `{}` in `[{}]`", term, text);
+            if let Some(value) = integer(&term.replace(' ', "")) {
+                memory.displacement += value;
+            } else if let Some((index, scale)) = term.split_once('*') {
+                let (index, scale) = match register(index.trim()) {
+                    Some(index) => (index, scale),
+                    None => (register(scale.trim()).with_context(invalid)?, index),
+                };
+                let scale = scale.trim().parse().ok().with_context(invalid)?;
+                ensure!([1, 2, 4, 8].contains(&scale), invalid());
+                ensure!(memory.index.is_none(), invalid());
+                (memory.index, memory.scale) = (Some(index), scale);
+            } else {
+                let register = register(term).with_context(invalid)?;
+                match (memory.base, memory.index) {
+                    (None, _) => memory.base = Some(register),
+                    (Some(_), None) => memory.index = Some(register),
+                    _ => bail!(invalid()),
+                }
+            }
+        }
+        Ok(memory)
+    }
+
+    fn operand(&mut self, text: &str) -> Result<Arg> {
+        let (size, text) = [("qword", 8), ("dword", 4), ("word", 2), ("byte", 1)]
+            .iter()
+            .find_map(|&(keyword, size)| {
+                text.strip_prefix(keyword)
+                    .filter(|rest| rest.starts_with([' ', '[']))
+                    .map(|rest| (size, rest.trim()))
+            })
+            .unwrap_or((0, text));
+        if let Some(memory) = text.strip_prefix('[').and_then(|t| t.strip_suffix(']')) {
+            return Ok(Arg::Memory(Memory {
+                size,
+                ..self.memory(memory.trim())?
+            }));
+        }
+        if let Some(register) = register(text) {
+            return Ok(Arg::Register(register));
+        }
+        if let Some(value) = integer(text) {
+            return Ok(Arg::Literal(value));
+        }
+        Ok(Arg::Address(self.target(text)?))
+    }
+
+    fn instruction(&mut self, mnemonic: &str, operands: &str) -> Result<Instruction> {
+        let operands: Vec<&str> = match operands.trim() {
+            "" => Vec::new(),
+            operands => operands.split(',').map(str::trim).collect(),
+        };
+        let arity = match mnemonic {
+            "ret" => 0,
+            "call" | "jmp" | "jne" | "je" | "jl" | "jle" | "jg" | "jge" | "push" | "pop"
+            | "not" | "sete" | "setne" | "setl" | "setle" | "setg" | "setge" => 1,
+            _ => 2,
+        };
+        if operands.len() != arity {
+            bail!(
+                "`{}` takes {} operand(s), found {}",
+                mnemonic,
+                arity,
+                operands.len()
+            );
+        }
+        if mnemonic == "call" {
+            return Ok(Instruction::Call(self.target(operands[0])?));
+        }
+        let mut args = operands
+            .iter()
+            .map(|operand| self.operand(operand))
+            .collect::<Result<Vec<Arg>>>()?;
+        // Memory without a size keyword is as wide as the register operand,
+        // as nasm takes it, or a qword like every a86 value
+        let register_size = args.iter().find_map(|arg| match arg {
+            Arg::Register(r) => Some(r.size()),
+            _ => None,
+        });
+        let mut width = register_size;
+        for arg in &mut args {
+            if let Arg::Memory(memory) = arg {
+                match mnemonic {
+                    "lea" => memory.size = 0,
+                    "movzx" if memory.size == 0 => {
+                        bail!("`movzx` needs the size of its memory operand")
+                    }
+                    _ if memory.size == 0 => memory.size = register_size.unwrap_or(8),
+                    _ => {}
+                }
+                width = width.or(Some(memory.size));
+            }
+        }
+        // Immediates are signed values of the operation's width, as decoded
+        if let Some(bits @ (8 | 16 | 32)) = width.map(|size| 8 * size as u32) {
+            for arg in &mut args {
+                if let Arg::Literal(lit) = arg
+                    && (1 << (bits - 1)..1 << bits).contains(lit)
+                {
+                    *lit -= 1 << bits;
+                }
+            }
+        }
+        Ok(match (mnemonic, args.as_slice()) {
+            ("ret", []) => Instruction::Ret,
+            ("jmp", &[a]) => Instruction::Jmp(a),
+            ("jne", &[a]) => Instruction::Jne(a),
+            ("je", &[a]) => Instruction::Je(a),
+            ("jl", &[a]) => Instruction::Jl(a),
+            ("jle", &[a]) => Instruction::Jle(a),
+            ("jg", &[a]) => Instruction::Jg(a),
+            ("jge", &[a]) => Instruction::Jge(a),
+            ("not", &[a]) => Instruction::Not(a),
+            ("sete", &[a]) => Instruction::Sete(a),
+            ("setne", &[a]) => Instruction::Setne(a),
+            ("setl", &[a]) => Instruction::Setl(a),
+            ("setle", &[a]) => Instruction::Setle(a),
+            ("setg", &[a]) => Instruction::Setg(a),
+            ("setge", &[a]) => Instruction::Setge(a),
+            ("push", &[a]) => Instruction::Push(a),
+            ("pop", &[a]) => Instruction::Pop(a),
+            ("add", &[a, b]) => Instruction::Add(a, b),
+            ("sub", &[a, b]) => Instruction::Sub(a, b),
+            ("and", &[a, b]) => Instruction::And(a, b),
+            ("or", &[a, b]) => Instruction::Or(a, b),
+            ("sal" | "shl", &[a, b]) => Instruction::Sal(a, b),
+            ("sar", &[a, b]) => Instruction::Sar(a, b),
+            ("shr", &[a, b]) => Instruction::Shr(a, b),
+            ("test", &[a, b]) => Instruction::Test(a, b),
+            ("movzx", &[a, b]) => Instruction::Movzx(a, b),
+            ("xor", &[a, b]) => Instruction::Xor(a, b),
+            ("mov", &[a, b]) => Instruction::Mov(a, b),
+            ("cmove", &[a, b]) => Instruction::Cmove(a, b),
+            ("cmovne", &[a, b]) => Instruction::Cmovne(a, b),
+            ("cmovl", &[a, b]) => Instruction::Cmovl(a, b),
+            ("cmovle", &[a, b]) => Instruction::Cmovle(a, b),
+            ("cmovg", &[a, b]) => Instruction::Cmovg(a, b),
+            ("cmovge", &[a, b]) => Instruction::Cmovge(a, b),
+            ("cmp", &[a, b]) => Instruction::Cmp(a, b),
+            ("lea", &[a, b]) => Instruction::Lea(a, b),
+            _ => bail!("unknown instruction `{}`", mnemonic),
+        })
+    }
+
+    fn line(&mut self, line: &str) -> Result<()> {
+        let line = line.split(';').next().unwrap().trim();
+        let (head, rest) = line
+            .split_once(char::is_whitespace)
+            .map_or((line, ""), |(head, rest)| (head, rest.trim()));
+        match head.to_lowercase().as_str() {
+            "" => {}
+            // Every label is a symbol, and externs were read beforehand
+            "global" | "extern" => {}
+            "section" if rest == ".text" => {}
+            "section" => bail!("only the .text section is supported, found {}", rest),
+            "default" => {}
+            _ if head.ends_with(':') => {
+                self.asm.bind(&head[..head.len() - 1])?;
+                return self.line(rest);
+            }
+            mnemonic => {
+                let instruction = self.instruction(mnemonic, rest)?;
+                self.asm.push(instruction);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The program in `source`, NASM assembly as the course compiler emits it
+pub fn parse(source: &str) -> Result<Program> {
+    let mut parser = Parser {
+        asm: Assembler::new(),
+        externs: HashMap::new(),
+    };
+    // Externs may be declared after they're used, so they're read first
+    for line in source.lines() {
+        let line = line.split(';').next().unwrap().trim();
+        if let Some(names) = line.strip_prefix("extern ") {
+            for name in names.split(',').map(str::trim) {
+                let address = EXTERN_ADDRESS + 16 * parser.externs.len() as Address;
+                parser.externs.entry(name.to_owned()).or_insert(address);
+            }
+        }
+    }
+    for (i, line) in source.lines().enumerate() {
+        parser
+            .line(line)
+            .with_context(|| format!("line {}: `{}`", i + 1, line.trim()))?;
+    }
+
+    let assembly = parser.asm.assemble(TEXT_ADDRESS)?;
+    let mut symbols: Vec<(String, Address)> = assembly.labels.into_iter().collect();
+    symbols.extend(parser.externs);
+    Program::from_text(&assembly.code, TEXT_ADDRESS, symbols, &[])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        a86::{Arg::*, Instruction::*, Register::*},
+        decompiler,
+    };
+
+    const READ_BYTE: &str = "        default rel
+        section .text
+        global entry
+        extern read_byte
+        extern raise_error
+entry:
+        push rbx
+        push r15
+        mov rbx, rdi
+        add rbx, 0
+        mov r15, rsp
+        and r15, 8
+        sub rsp, r15
+        call read_byte
+        add rsp, r15
+        mov r9, rax
+        and r9, 15 ; integer?
+        cmp r9, 0
+        jne err
+        add rax, 16
+        add rsp, 0
+        pop r15
+        pop rbx
+        ret
+err:
+        mov r15, rsp
+        and r15, 8
+        sub rsp, r15
+        call raise_error
+";
+
+    #[test]
+    fn parses_course_assembly() {
+        let program = parse(READ_BYTE).unwrap();
+        assert_eq!(program.entry_point(), TEXT_ADDRESS);
+        assert_eq!(program.label(EXTERN_ADDRESS).unwrap(), "read_byte");
+        let program = decompiler::parse(&program).unwrap();
+        assert_eq!(program.to_string(), "#lang racket\n(add1 (read-byte))");
+    }
+
+    #[test]
+    fn parses_operands() {
+        let mut parser = Parser {
+            asm: Assembler::new(),
+            externs: HashMap::new(),
+        };
+        let memory = |m| Arg::Memory(m);
+        assert_eq!(
+            parser.operand("[rbx + 8]").unwrap(),
+            memory(super::Memory {
+                size: 0,
+                ..super::Memory::offset(Rbx, 8)
+            })
+        );
+        assert_eq!(
+            parser.operand("[rbx + r8*8 - 3]").unwrap(),
+            memory(super::Memory {
+                base: Some(Rbx),
+                index: Some(R8),
+                scale: 8,
+                displacement: -3,
+                rip_relative: false,
+                size: 0,
+            })
+        );
+        assert_eq!(
+            parser.operand("qword [rsp - 0x10]").unwrap(),
+            memory(super::Memory::offset(Rsp, -16))
+        );
+        assert_eq!(parser.operand("-1").unwrap(), Literal(-1));
+        assert_eq!(
+            parser.instruction("mov", "[rbx + 0], rax").unwrap(),
+            Mov(memory(super::Memory::offset(Rbx, 0)), Register(Rax))
+        );
+        assert_eq!(
+            parser.instruction("shl", "rax, 4").unwrap(),
+            Sal(Register(Rax), Literal(4))
+        );
+        assert_eq!(
+            parser.instruction("movzx", "eax, byte [rbx]").unwrap(),
+            Movzx(
+                Register(Eax),
+                memory(super::Memory {
+                    size: 1,
+                    ..super::Memory::offset(Rbx, 0)
+                })
+            )
+        );
+        assert_eq!(
+            parser.instruction("cmp", "dword [rax], 0").unwrap(),
+            Cmp(
+                memory(super::Memory {
+                    size: 4,
+                    ..super::Memory::offset(Rax, 0)
+                }),
+                Literal(0)
+            )
+        );
+        assert_eq!(
+            parser.instruction("mov", "al, [rbx]").unwrap(),
+            Mov(
+                Register(Al),
+                memory(super::Memory {
+                    size: 1,
+                    ..super::Memory::offset(Rbx, 0)
+                })
+            )
+        );
+        assert!(parser.instruction("movzx", "eax, [rbx]").is_err());
+        assert_eq!(
+            parser.instruction("mov", "eax, 0xffffffff").unwrap(),
+            Mov(Register(Eax), Literal(-1))
+        );
+        assert_eq!(
+            parser.instruction("cmp", "byte [rbx], 0x80").unwrap(),
+            Cmp(
+                memory(super::Memory {
+                    size: 1,
+                    ..super::Memory::offset(Rbx, 0)
+                }),
+                Literal(-0x80)
+            )
+        );
+
+        let error = parse("entry:\n  push rbx\n  frob rax, 1\n").unwrap_err();
+        assert!(format!("{:#}", error).starts_with("line 3: `frob rax, 1`"));
+        let error = parse("entry:\n  mov rax, [rbx - 8 - rcx]\n").unwrap_err();
+        assert!(
+            format!("{:#}", error).contains("invalid term `- rcx` in `[rbx - 8 - rcx]`"),
+            "{:#}",
+            error
+        );
+        let error = parse("section .data\n").unwrap_err();
+        assert!(format!("{:#}", error).contains("only the .text section"));
+    }
+}
@@ -0,0 +1,424 @@
+use std::collections::HashMap;
+
+use crate::{
+    loot::{Datum, Defn, Expr, Id, Operation, Pattern, Program},
+    sexp::{Sexp, SexpKind, SyntaxError, read_all},
+};
+
+/*
+  Parses Loot source into the AST, following the course's parse.rkt. Every
+  binding occurrence gets a fresh `Id`, so shadowed variables stay distinct;
+  free variables get one `Id` per name.
+*/
+
+type Op1 = fn(Box<Expr>) -> Operation;
+type Op2 = fn(Box<Expr>, Box<Expr>) -> Operation;
+
+fn op1(name: &str) -> Option<Op1> {
+    Some(match name {
+        "add1" => Operation::Add1,
+        "sub1" => Operation::Sub1,
+        "zero?" => Operation::ZeroHuh,
+        "char?" => Operation::CharHuh,
+        "integer->char" => Operation::IntegerToChar,
+        "char->integer" => Operation::CharToInteger,
+        "write-byte" => Operation::WriteByte,
+        "eof-object?" => Operation::EofObjectHuh,
+        "box" => Operation::Box,
+        "car" => Operation::Car,
+        "cdr" => Operation::Cdr,
+        "unbox" => Operation::Unbox,
+        "empty?" => Operation::EmptyHuh,
+        "cons?" => Operation::ConsHuh,
+        "box?" => Operation::BoxHuh,
+        "vector?" => Operation::VectorHuh,
+        "vector-length" => Operation::VectorLength,
+        "string?" => Operation::StringHuh,
+        "string-length" => Operation::StringLength,
+        _ => return None,
+    })
+}
+
+fn op2(name: &str) -> Option<Op2> {
+    Some(match name {
+        "+" => Operation::Plus,
+        "-" => Operation::Sub,
+        "<" => Operation::Less,
+        "=" => Operation::Equal,
+        "eq?" => Operation::EqHuh,
+        "cons" => Operation::Cons,
+        "make-vector" => Operation::MakeVector,
+        "vector-ref" => Operation::VectorRef,
+        "make-string" => Operation::MakeString,
+        "string-ref" => Operation::StringRef,
+        _ => return None,
+    })
+}
+
+/// Whether `name` is a keyword or primitive, rather than a variable
+pub fn is_reserved(name: &str) -> bool {
+    op1(name).is_some()
+        || op2(name).is_some()
+        || matches!(
+            name,
+            "read-byte"
+                | "peek-byte"
+                | "void"
+                | "vector-set!"
+                | "begin"
+                | "if"
+                | "let"
+                | "lambda"
+                | "λ"
+                | "match"
+                | "quote"
+                | "define"
+                | "eof"
+                | "and"
+                | "_"
+        )
+}
+
+struct Parser<'a> {
+    source: &'a str,
+    /// Names of every `Id` handed out, indexed by `Id`
+    names: Vec<String>,
+    /// Bindings in scope, innermost last
+    scope: Vec<(String, Id)>,
+    /// `Id`s of free variables
+    globals: HashMap<String, Id>,
+}
+
+impl Parser<'_> {
+    fn error(&self, sexp: &Sexp, message: impl Into<String>) -> SyntaxError {
+        SyntaxError::new(self.source, sexp.span.clone(), message)
+    }
+
+    fn fresh(&mut self, name: &str) -> Id {
+        self.names.push(name.to_owned());
+        self.names.len() - 1
+    }
+
+    fn lookup(&mut self, name: &str) -> Id {
+        if let Some(&(_, id)) = self.scope.iter().rev().find(|(n, _)| n == name) {
+            return id;
+        }
+        if let Some(&id) = self.globals.get(name) {
+            return id;
+        }
+        let id = self.fresh(name);
+        self.globals.insert(name.to_owned(), id);
+        id
+    }
+
+    fn identifier<'s>(&self, sexp: &'s Sexp) -> Result<&'s str, SyntaxError> {
+        sexp.symbol()
+            .ok_or_else(|| self.error(sexp, "expected an identifier"))
+    }
+
+    fn datum(sexp: &Sexp) -> Option<Datum> {
+        Some(match &sexp.kind {
+            SexpKind::Integer(i) => Datum::Integer(*i),
+            SexpKind::Boolean(b) => Datum::Boolean(*b),
+            SexpKind::Character(c) => Datum::Character(*c),
+            SexpKind::String(s) => Datum::String(s.clone()),
+            SexpKind::List(items) if sexp.is_form("quote") && items.len() == 2 => {
+                match items[1].list() {
+                    Some([]) => Datum::Empty,
+                    _ => return None,
+                }
+            }
+            _ => return None,
+        })
+    }
+
+    fn exprs(&mut self, sexps: &[Sexp]) -> Result<Vec<Expr>, SyntaxError> {
+        sexps.iter().map(|s| self.expr(s)).collect()
+    }
+
+    fn expr(&mut self, sexp: &Sexp) -> Result<Expr, SyntaxError> {
+        if let Some(datum) = Self::datum(sexp) {
+            return Ok(Expr::Literal(datum));
+        }
+        let items = match &sexp.kind {
+            SexpKind::Symbol(s) if s == "eof" => return Ok(Expr::Literal(Datum::Eof)),
+            SexpKind::Symbol(s) => return Ok(Expr::Var(self.lookup(s))),
+            SexpKind::List(items) => items.as_slice(),
+            _ => unreachable!("all atoms are data"),
+        };
+        let Some((head, args)) = items.split_first() else {
+            return Err(self.error(sexp, "empty application"));
+        };
+
+        let bx = Box::new;
+        Ok(match (head.symbol(), args) {
+            (Some("read-byte"), []) => Expr::Op(Operation::ReadByte),
+            (Some("peek-byte"), []) => Expr::Op(Operation::PeekByte),
+            (Some("void"), []) => Expr::Op(Operation::Void),
+            (Some(name), [e]) if op1(name).is_some() => {
+                Expr::Op(op1(name).unwrap()(bx(self.expr(e)?)))
+            }
+            (Some(name), [e1, e2]) if op2(name).is_some() => {
+                Expr::Op(op2(name).unwrap()(bx(self.expr(e1)?), bx(self.expr(e2)?)))
+            }
+            (Some("vector-set!"), [e1, e2, e3]) => Expr::Op(Operation::VectorSetBang(
+                bx(self.expr(e1)?),
+                bx(self.expr(e2)?),
+                bx(self.expr(e3)?),
+            )),
+            (Some("begin"), [_, ..]) => Expr::Begin(self.exprs(args)?),
+            (Some("if"), [e1, e2, e3]) => {
+                Expr::If(bx(self.expr(e1)?), bx(self.expr(e2)?), bx(self.expr(e3)?))
+            }
+            (Some("let"), [bindings, body]) => {
+                let [binding] = bindings.list().unwrap_or_default() else {
+                    return Err(self.error(bindings, "expected a single `[x e]` binding"));
+                };
+                let Some([x, e]) = binding.list() else {
+                    return Err(self.error(binding, "expected a `[x e]` binding"));
+                };
+                let name = self.identifier(x)?;
+                let e = self.expr(e)?;
+                let id = self.fresh(name);
+                self.scope.push((name.to_owned(), id));
+                let body = self.expr(body);
+                self.scope.pop();
+                Expr::Let(id, bx(e), bx(body?))
+            }
+            (Some("lambda" | "λ"), [params, body]) => {
+                let lambda = self.fresh("lambda");
+                let (params, body) = self.function(params, body)?;
+                Expr::Lam(lambda, params, bx(body))
+            }
+            (Some("match"), [e, clauses @ ..]) => {
+                let e = self.expr(e)?;
+                let mut patterns = Vec::new();
+                let mut bodies = Vec::new();
+                for clause in clauses {
+                    let Some([pattern, body]) = clause.list() else {
+                        return Err(self.error(clause, "expected a `[pattern e]` clause"));
+                    };
+                    let depth = self.scope.len();
+                    patterns.push(self.pattern(pattern)?);
+                    let body = self.expr(body);
+                    self.scope.truncate(depth);
+                    bodies.push(body?);
+                }
+                Expr::Match(bx(e), patterns, bodies)
+            }
+            (
+                Some(
+                    keyword @ ("begin" | "if" | "let" | "lambda" | "λ" | "match" | "quote"
+                    | "define"),
+                ),
+                _,
+            ) => return Err(self.error(sexp, format!("bad `{}` form", keyword))),
+            (Some(name), _)
+                if op1(name).is_some()
+                    || op2(name).is_some()
+                    || matches!(name, "read-byte" | "peek-byte" | "void" | "vector-set!") =>
+            {
+                return Err(self.error(sexp, format!("wrong number of arguments to `{}`", name)));
+            }
+            _ => Expr::App(bx(self.expr(head)?), self.exprs(args)?),
+        })
+    }
+
+    /// Parses the parameters and body of a function, with the parameters in scope
+    fn function(&mut self, params: &Sexp, body: &Sexp) -> Result<(Vec<Id>, Expr), SyntaxError> {
+        let params = params
+            .list()
+            .ok_or_else(|| self.error(params, "expected a parameter list"))?;
+        let depth = self.scope.len();
+        let mut ids = Vec::new();
+        for param in params {
+            let name = self.identifier(param)?;
+            let id = self.fresh(name);
+            self.scope.push((name.to_owned(), id));
+            ids.push(id);
+        }
+        let body = self.expr(body);
+        self.scope.truncate(depth);
+        Ok((ids, body?))
+    }
+
+    /// Parses a pattern, bringing the variables it binds into scope
+    fn pattern(&mut self, sexp: &Sexp) -> Result<Pattern, SyntaxError> {
+        if let Some(datum) = Self::datum(sexp) {
+            return Ok(Pattern::Literal(datum));
+        }
+        if let Some(name) = sexp.symbol() {
+            // `_` binds nothing, which a variable nobody refers to models exactly
+            let id = self.fresh(name);
+            if name != "_" {
+                self.scope.push((name.to_owned(), id));
+            }
+            return Ok(Pattern::Var(id));
+        }
+        let bx = Box::new;
+        Ok(match sexp.list().unwrap_or_default() {
+            [head, p] if head.symbol() == Some("box") => Pattern::Box(bx(self.pattern(p)?)),
+            [head, p1, p2] if head.symbol() == Some("cons") => {
+                Pattern::Cons(bx(self.pattern(p1)?), bx(self.pattern(p2)?))
+            }
+            [head, p1, p2] if head.symbol() == Some("and") => {
+                Pattern::Conj(bx(self.pattern(p1)?), bx(self.pattern(p2)?))
+            }
+            _ => return Err(self.error(sexp, "unsupported pattern")),
+        })
+    }
+}
+
+/// Parses a whole `.rkt` file: any number of `define`s followed by one expression
+pub fn parse_program(source: &str) -> Result<Program, SyntaxError> {
+    let sexps = read_all(source)?;
+    let mut parser = Parser {
+        source,
+        names: Vec::new(),
+        scope: Vec::new(),
+        globals: HashMap::new(),
+    };
+
+    let Some((expr, defines)) = sexps.split_last() else {
+        return Err(SyntaxError::new(
+            source,
+            0..source.len(),
+            "program has no expression",
+        ));
+    };
+
+    // Functions are in scope in every definition, so bind all the names first
+    let mut signatures = Vec::new();
+    for define in defines {
+        let signature = match define.list() {
+            Some([head, signature, _]) if head.symbol() == Some("define") => signature,
+            _ => return Err(parser.error(define, "expected a `define`")),
+        };
+        let Some([name, params @ ..]) = signature.list() else {
+            return Err(parser.error(signature, "expected `(name param ...)`"));
+        };
+        let name = parser.identifier(name)?;
+        let id = parser.fresh(name);
+        parser.scope.push((name.to_owned(), id));
+        signatures.push((id, params));
+    }
+
+    let mut defns = Vec::new();
+    for (define, (id, params)) in defines.iter().zip(signatures) {
+        let body = &define.list().unwrap()[2];
+        let params = Sexp {
+            kind: SexpKind::List(params.to_vec()),
+            span: define.span.clone(),
+        };
+        let (params, body) = parser.function(&params, body)?;
+        defns.push(Defn(id, params, Box::new(body)));
+    }
+
+    let expr = parser.expr(expr)?;
+    Ok(Program {
+        defines: defns,
+        expr: Box::new(expr),
+        names: parser.names.into_iter().enumerate().collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn parses_test_programs() {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/test-programs");
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_some_and(|ext| ext == "rkt") {
+                let source = fs::read_to_string(&path).unwrap();
+                parse_program(&source).unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+            }
+        }
+    }
+
+    #[test]
+    fn parses_forms() {
+        let program = parse_program("(if (zero? 0) (add1 #\\a) (begin (void) '() 1))").unwrap();
+        assert_eq!(
+            program.to_string(),
+            "#lang racket\n(if (zero? 0) (add1 #\\a) (begin (void) '() 1))"
+        );
+
+        let program = parse_program(
+            "(define (f x) (let ([x (+ x 1)]) x)) (match (f 1) [(cons a _) a] [b (f b)])",
+        )
+        .unwrap();
+        let Defn(f, params, body) = &program.defines[0];
+        let Expr::Let(x2, value, inner) = body.as_ref() else {
+            panic!("expected a let, got {:?}", body)
+        };
+        assert_eq!(
+            **value,
+            Expr::Op(Operation::Plus(
+                Box::new(Expr::Var(params[0])),
+                Box::new(Expr::Literal(Datum::Integer(1)))
+            ))
+        );
+        assert_eq!(**inner, Expr::Var(*x2));
+        assert_ne!(params[0], *x2);
+
+        let Expr::Match(scrutinee, patterns, bodies) = program.expr.as_ref() else {
+            panic!("expected a match, got {:?}", program.expr)
+        };
+        let Expr::App(callee, _) = scrutinee.as_ref() else {
+            panic!("expected an application")
+        };
+        assert_eq!(**callee, Expr::Var(*f));
+        let Pattern::Cons(a, _) = &patterns[0] else {
+            panic!("expected a cons pattern")
+        };
+        assert_eq!(
+            bodies[0],
+            Expr::Var(match **a {
+                Pattern::Var(a) => a,
+                _ => unreachable!(),
+            })
+        );
+    }
+
+    #[test]
+    fn printed_programs_parse_back() {
+        let program =
+            parse_program(r#"(define (f) (string-length "\0\a\b\v\177")) (begin (f) 1)"#).unwrap();
+        let Expr::Op(Operation::StringLength(s)) = program.defines[0].2.as_ref() else {
+            panic!("expected string-length, got {:?}", program.defines[0].2)
+        };
+        assert_eq!(
+            **s,
+            Expr::Literal(Datum::String("\0\u{7}\u{8}\u{b}\u{7f}".to_owned()))
+        );
+
+        let printed = program.to_string();
+        let reparsed = parse_program(&printed).unwrap_or_else(|e| panic!("{}\n{}", printed, e));
+        assert!(
+            crate::alpha::alpha_equivalent(&program, &reparsed),
+            "{}",
+            printed
+        );
+    }
+
+    #[test]
+    fn reports_errors_with_locations() {
+        let e = parse_program("#lang racket\n(if 1 2)").unwrap_err();
+        assert_eq!(
+            (e.line, e.column, e.message.as_str()),
+            (2, 1, "bad `if` form")
+        );
+        let e = parse_program("(add1 1 2)").unwrap_err();
+        assert_eq!(e.message, "wrong number of arguments to `add1`");
+        let e = parse_program("(let ([1 2]) 3)").unwrap_err();
+        assert_eq!(
+            (e.span, e.message.as_str()),
+            (7..8, "expected an identifier")
+        );
+    }
+}
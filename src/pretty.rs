@@ -0,0 +1,168 @@
+/*
+  A Wadler-style pretty-printer ("A prettier printer", with Leijen's `align`).
+
+  A `Doc` describes every way some text may be laid out: each `group` is
+  printed on one line if it fits in the remaining width, and otherwise has all
+  of its `line`s broken, each starting a new line at the current indentation.
+*/
+
+/// Line width used when none is given
+pub const DEFAULT_WIDTH: usize = 80;
+
+#[derive(Debug, Clone)]
+pub enum Doc {
+    Text(String),
+    /// A space, or a newline if the enclosing group is broken
+    Line,
+    Concat(Vec<Doc>),
+    /// Indents broken lines inside by this much more
+    Nest(usize, Box<Doc>),
+    /// Indents broken lines inside to the column the doc starts at
+    Align(Box<Doc>),
+    Group(Box<Doc>),
+}
+
+pub fn text(s: impl Into<String>) -> Doc {
+    Doc::Text(s.into())
+}
+
+pub fn line() -> Doc {
+    Doc::Line
+}
+
+pub fn concat(docs: impl IntoIterator<Item = Doc>) -> Doc {
+    Doc::Concat(docs.into_iter().collect())
+}
+
+pub fn nest(indent: usize, doc: Doc) -> Doc {
+    Doc::Nest(indent, Box::new(doc))
+}
+
+pub fn align(doc: Doc) -> Doc {
+    Doc::Align(Box::new(doc))
+}
+
+pub fn group(doc: Doc) -> Doc {
+    Doc::Group(Box::new(doc))
+}
+
+/// `docs` separated by `line`s
+pub fn lines(docs: impl IntoIterator<Item = Doc>) -> Doc {
+    let mut parts = Vec::new();
+    for doc in docs {
+        if !parts.is_empty() {
+            parts.push(line());
+        }
+        parts.push(doc);
+    }
+    Doc::Concat(parts)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Mode {
+    Flat,
+    Break,
+}
+
+/// Whether the text up to the next broken line fits in `remaining` columns,
+/// with `doc` laid out flat and then the `rest` of the document as planned
+fn fits(mut remaining: usize, doc: &Doc, rest: &[(usize, Mode, &Doc)]) -> bool {
+    let mut stack: Vec<(Mode, &Doc)> = vec![(Mode::Flat, doc)];
+    let mut rest = rest.iter().rev();
+    loop {
+        let (mode, doc) = match stack.pop() {
+            Some(item) => item,
+            None => match rest.next() {
+                Some(&(_, mode, doc)) => (mode, doc),
+                None => return true,
+            },
+        };
+        match doc {
+            Doc::Text(s) => match remaining.checked_sub(s.chars().count()) {
+                Some(r) => remaining = r,
+                None => return false,
+            },
+            Doc::Line if mode == Mode::Break => return true,
+            Doc::Line => match remaining.checked_sub(1) {
+                Some(r) => remaining = r,
+                None => return false,
+            },
+            Doc::Concat(docs) => stack.extend(docs.iter().rev().map(|doc| (mode, doc))),
+            Doc::Nest(_, doc) | Doc::Align(doc) | Doc::Group(doc) => stack.push((mode, doc)),
+        }
+    }
+}
+
+impl Doc {
+    /// Lays the document out in lines of at most `width` columns, where possible
+    pub fn render(&self, width: usize) -> String {
+        let mut out = String::new();
+        let mut column = 0;
+        // Documents still to print, with their indentation, last first
+        let mut stack: Vec<(usize, Mode, &Doc)> = vec![(0, Mode::Break, self)];
+        while let Some((indent, mode, doc)) = stack.pop() {
+            match doc {
+                Doc::Text(s) => {
+                    out.push_str(s);
+                    column += s.chars().count();
+                }
+                Doc::Line if mode == Mode::Flat => {
+                    out.push(' ');
+                    column += 1;
+                }
+                Doc::Line => {
+                    out.push('\n');
+                    out.extend(std::iter::repeat_n(' ', indent));
+                    column = indent;
+                }
+                Doc::Concat(docs) => stack.extend(docs.iter().rev().map(|doc| (indent, mode, doc))),
+                Doc::Nest(more, doc) => stack.push((indent + more, mode, doc)),
+                Doc::Align(doc) => stack.push((column, mode, doc)),
+                Doc::Group(doc) => {
+                    let mode =
+                        if mode == Mode::Flat || fits(width.saturating_sub(column), doc, &stack) {
+                            Mode::Flat
+                        } else {
+                            Mode::Break
+                        };
+                    stack.push((indent, mode, doc));
+                }
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(name: &str, args: Vec<Doc>) -> Doc {
+        group(concat([
+            text(format!("({} ", name)),
+            align(lines(args)),
+            text(")"),
+        ]))
+    }
+
+    #[test]
+    fn groups_break_only_when_too_wide() {
+        let doc = call(
+            "f",
+            vec![text("aaaa"), call("g", vec![text("bb"), text("cc")])],
+        );
+        assert_eq!(doc.render(80), "(f aaaa (g bb cc))");
+        assert_eq!(doc.render(16), "(f aaaa\n   (g bb cc))");
+        assert_eq!(doc.render(8), "(f aaaa\n   (g bb\n      cc))");
+    }
+
+    #[test]
+    fn nest_indents_relative_to_enclosing_lines() {
+        let doc = group(concat([
+            text("(begin"),
+            nest(2, concat([line(), text("a"), line(), text("b")])),
+            text(")"),
+        ]));
+        assert_eq!(doc.render(4), "(begin\n  a\n  b)");
+    }
+}
@@ -0,0 +1,197 @@
+use std::{fmt::Write, ops::Range};
+
+use crate::{
+    a86::{Address, Program as A86Program},
+    decompiler::{Origin, Origins},
+    loot::{Expr, Names, Program as LootProgram},
+};
+
+/*
+  Provenance of a decompiled program: for every node of the AST, the
+  instructions it was reconstructed from. Nodes are numbered in pre-order,
+  definitions first, the same as in the JSON output.
+*/
+
+#[derive(Debug, Clone)]
+pub struct NodeProvenance<'a> {
+    pub id: usize,
+    pub parent: Option<usize>,
+    pub expr: &'a Expr,
+    /// Indices of every instruction the node was decompiled from, including
+    /// those of its subexpressions, if known
+    pub instructions: Option<Range<usize>>,
+    /// Indices of the instructions matched for the node itself
+    pub own: Vec<Range<usize>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Provenance<'a> {
+    nodes: Vec<NodeProvenance<'a>>,
+}
+
+impl<'a> Provenance<'a> {
+    pub fn new(program: &'a LootProgram, origins: &Origins) -> Self {
+        let mut provenance = Self { nodes: Vec::new() };
+        for (i, defn) in program.defines.iter().enumerate() {
+            provenance.add(&defn.2, origins.defines.get(i), None);
+        }
+        provenance.add(&program.expr, Some(&origins.expr), None);
+        provenance
+    }
+
+    fn add(&mut self, expr: &'a Expr, origin: Option<&Origin>, parent: Option<usize>) {
+        let id = self.nodes.len();
+        self.nodes.push(NodeProvenance {
+            id,
+            parent,
+            expr,
+            instructions: origin.map(|o| o.instructions.clone()),
+            own: origin.map(|o| o.own.clone()).unwrap_or_default(),
+        });
+        for (i, child) in expr.children().into_iter().enumerate() {
+            self.add(child, origin.and_then(|o| o.children.get(i)), Some(id));
+        }
+    }
+
+    pub fn nodes(&self) -> &[NodeProvenance<'a>] {
+        &self.nodes
+    }
+
+    pub fn node(&self, id: usize) -> Option<&NodeProvenance<'a>> {
+        self.nodes.get(id)
+    }
+
+    /// The node that the instruction at `index` was matched for, if any
+    pub fn owner(&self, index: usize) -> Option<&NodeProvenance<'a>> {
+        self.nodes
+            .iter()
+            .find(|node| node.own.iter().any(|range| range.contains(&index)))
+    }
+
+    /// The addresses of the instructions node `id` was decompiled from, from
+    /// the first to just past the last
+    pub fn addresses(&self, id: usize, binary: &A86Program) -> Option<Range<Address>> {
+        let instructions = self.node(id)?.instructions.clone()?;
+        Some(
+            binary.index_to_address(instructions.start)?
+                ..binary.index_to_address(instructions.end)?,
+        )
+    }
+}
+
+/// A node's expression on one line, shortened to about `max` characters
+fn fragment(expr: &Expr, names: &Names, max: usize) -> String {
+    let text = expr.to_doc(names).render(usize::MAX);
+    if text.chars().count() <= max {
+        return text;
+    }
+    let mut short: String = text.chars().take(max - 3).collect();
+    short.push_str("...");
+    short
+}
+
+/// The disassembly of `binary` with labels, each instruction annotated with
+/// the fragment of `program` it was decompiled into
+pub fn listing(program: &LootProgram, origins: &Origins, binary: &A86Program) -> String {
+    let provenance = Provenance::new(program, origins);
+    let names = program.display_names();
+
+    let mut out = String::new();
+    let mut previous = None;
+    for (index, instruction) in binary.instructions().iter().enumerate() {
+        let address = binary.index_to_address(index).unwrap();
+        let mut labels: Vec<String> = binary.address_to_symbols(address).into_iter().collect();
+        labels.sort();
+        for label in labels {
+            writeln!(out, "{}:", label).unwrap();
+        }
+
+        let line = format!("  {:#x}  {}", address, instruction);
+        let owner = provenance.owner(index);
+        match owner {
+            // Only the first of a run of instructions for the same node is annotated
+            Some(node) if previous != Some(node.id) => writeln!(
+                out,
+                "{:<40}; #{} {}",
+                line,
+                node.id,
+                fragment(node.expr, &names, 60)
+            ),
+            _ => writeln!(out, "{}", line),
+        }
+        .unwrap();
+        previous = owner.map(|node| node.id);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use super::*;
+    use crate::decompiler::parse_with_origins;
+
+    fn decompile(name: &str) -> (A86Program, LootProgram, Origins) {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("test-programs")
+            .join(name);
+        let binary = A86Program::from_elf_file(path).unwrap();
+        let (program, origins) = parse_with_origins(&binary).unwrap();
+        (binary, program, origins)
+    }
+
+    #[test]
+    fn every_body_instruction_has_one_owner() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("test-programs");
+        for entry in fs::read_dir(dir).unwrap() {
+            let name = entry.unwrap().file_name().into_string().unwrap();
+            if !name.ends_with(".run") {
+                continue;
+            }
+            let (_, program, origins) = decompile(&name);
+            let provenance = Provenance::new(&program, &origins);
+            let root = provenance.node(0).unwrap();
+            for index in root.instructions.clone().unwrap() {
+                let owners = provenance
+                    .nodes()
+                    .iter()
+                    .filter(|node| node.own.iter().any(|range| range.contains(&index)))
+                    .count();
+                assert_eq!(owners, 1, "{}: instruction {}", name, index);
+            }
+            // Nodes lie within their parents
+            for node in provenance.nodes() {
+                if let Some(parent) = node.parent {
+                    let outer = provenance
+                        .node(parent)
+                        .unwrap()
+                        .instructions
+                        .clone()
+                        .unwrap();
+                    let inner = node.instructions.clone().unwrap();
+                    assert!(outer.start <= inner.start && inner.end <= outer.end);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn listing_annotates_instructions() {
+        let (binary, program, origins) = decompile("add1.run");
+        let listing = listing(&program, &origins, &binary);
+        let entry = binary.entry_point();
+        assert!(
+            listing.starts_with(&format!("entry:\n  {:#x}  push rbx\n", entry)),
+            "{}",
+            listing
+        );
+        let add1 = listing.lines().find(|line| line.contains("; #2 ")).unwrap();
+        assert!(
+            add1.contains("mov r9, rax") && add1.ends_with("(add1 (if #t 160 999))"),
+            "{}",
+            listing
+        );
+        assert!(listing.contains("\nerr:\n"));
+    }
+}
@@ -0,0 +1,525 @@
+use std::ops::Range;
+
+/// Byte offsets into the source an s-expression was read from
+pub type Span = Range<usize>;
+
+/// An s-expression, as read from Racket source
+#[derive(Debug, Clone)]
+pub struct Sexp {
+    pub kind: SexpKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SexpKind {
+    Integer(i64),
+    Boolean(bool),
+    Character(char),
+    String(String),
+    Symbol(String),
+    List(Vec<Sexp>),
+}
+
+impl Sexp {
+    pub fn symbol(&self) -> Option<&str> {
+        match &self.kind {
+            SexpKind::Symbol(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn list(&self) -> Option<&[Sexp]> {
+        match &self.kind {
+            SexpKind::List(items) => Some(items),
+            _ => None,
+        }
+    }
+
+    /// Whether this is a list whose head is the symbol `head`
+    pub fn is_form(&self, head: &str) -> bool {
+        self.list()
+            .and_then(|items| items.first())
+            .and_then(Sexp::symbol)
+            == Some(head)
+    }
+}
+
+/// Spans are ignored, so that s-expressions read from different sources compare equal
+impl PartialEq for Sexp {
+    fn eq(&self, other: &Self) -> bool {
+        self.kind == other.kind
+    }
+}
+
+impl std::fmt::Display for Sexp {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.kind {
+            SexpKind::Integer(i) => write!(f, "{}", i),
+            SexpKind::Boolean(true) => write!(f, "#t"),
+            SexpKind::Boolean(false) => write!(f, "#f"),
+            SexpKind::Character(c) => match char_name(*c) {
+                Some(name) => write!(f, "#\\{}", name),
+                None => write!(f, "#\\{}", c),
+            },
+            SexpKind::String(s) => write!(f, "{}", string_literal(s)),
+            SexpKind::Symbol(s) => write!(f, "{}", s),
+            SexpKind::List(items) => {
+                write!(f, "(")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+/// Racket's names for characters that can't be written literally after `#\`
+const CHAR_NAMES: &[(&str, char)] = &[
+    ("nul", '\0'),
+    ("null", '\0'),
+    ("backspace", '\u{8}'),
+    ("tab", '\t'),
+    ("newline", '\n'),
+    ("linefeed", '\n'),
+    ("vtab", '\u{b}'),
+    ("page", '\u{c}'),
+    ("return", '\r'),
+    ("space", ' '),
+    ("rubout", '\u{7f}'),
+    ("delete", '\u{7f}'),
+];
+
+pub fn char_name(c: char) -> Option<&'static str> {
+    CHAR_NAMES
+        .iter()
+        .find(|&&(_, named)| named == c)
+        .map(|&(name, _)| name)
+}
+
+/// `s` as a Racket string literal. Control characters are escaped (as `\u`
+/// escapes unless they have a shorter one); Racket reads `\0` as the start
+/// of an octal escape, so NUL is `\u0000` too.
+pub fn string_literal(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out += "\\\"",
+            '\\' => out += "\\\\",
+            '\n' => out += "\\n",
+            '\t' => out += "\\t",
+            '\r' => out += "\\r",
+            c if c.is_control() => out += &format!("\\u{:04x}", c as u32),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// `source` with every non-ASCII character in string and character literals
+/// written as a `\\u` escape, which Racket reads back as the same character
+pub fn to_ascii(source: &str) -> String {
+    let escape = |c: char| match c as u32 {
+        code @ ..=0xffff => format!("u{:04x}", code),
+        code => format!("U{:08x}", code),
+    };
+    let mut out = String::with_capacity(source.len());
+    let mut chars = source.chars().peekable();
+    let mut in_string = false;
+    while let Some(c) = chars.next() {
+        match c {
+            // Whatever follows a backslash is escaped, including quotes
+            '\\' => {
+                out.push(c);
+                match chars.next() {
+                    Some(c) if !c.is_ascii() => out += &escape(c),
+                    Some(c) => out.push(c),
+                    None => {}
+                }
+            }
+            '#' if !in_string && chars.peek() == Some(&'\\') => {
+                out.push(c);
+                out.push(chars.next().unwrap());
+                match chars.next() {
+                    Some(c) if !c.is_ascii() => out += &escape(c),
+                    Some(c) => out.push(c),
+                    None => {}
+                }
+            }
+            '"' => {
+                in_string = !in_string;
+                out.push(c);
+            }
+            _ if in_string && !c.is_ascii() => {
+                out.push('\\');
+                out += &escape(c);
+            }
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// An error in the source text, with its location
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyntaxError {
+    pub span: Span,
+    /// 1-based line and column of the start of `span`
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl SyntaxError {
+    pub fn new(source: &str, span: Span, message: impl Into<String>) -> Self {
+        let before = &source[..span.start.min(source.len())];
+        let line = before.matches('\n').count() + 1;
+        let column = before.chars().rev().take_while(|&c| c != '\n').count() + 1;
+        Self {
+            span,
+            line,
+            column,
+            message: message.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl std::error::Error for SyntaxError {}
+
+fn is_integer(token: &str) -> bool {
+    let digits = token.strip_prefix(['+', '-']).unwrap_or(token);
+    !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit())
+}
+
+/// Whether Racket reads `token` as a number, as it does `1.5`, `.5`, `1e3`,
+/// `1/2` and `+inf.0`, rather than as a symbol
+fn is_number(token: &str) -> bool {
+    if matches!(token, "+inf.0" | "-inf.0" | "+nan.0" | "-nan.0") {
+        return true;
+    }
+    let unsigned = token.strip_prefix(['+', '-']).unwrap_or(token);
+    if let Some((numerator, denominator)) = unsigned.split_once('/') {
+        let digits = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_digit());
+        return digits(numerator) && digits(denominator);
+    }
+    // Rust reads the same decimals, but also `inf` and `nan`, which are symbols
+    unsigned.starts_with(|c: char| c.is_ascii_digit() || c == '.')
+        && unsigned.parse::<f64>().is_ok()
+}
+
+fn is_delimiter(c: char) -> bool {
+    c.is_whitespace() || matches!(c, '(' | ')' | '[' | ']' | '"' | ';' | '\'')
+}
+
+struct Reader<'a> {
+    source: &'a str,
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn peek(&self) -> Option<char> {
+        self.source[self.pos..].chars().next()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    fn error(&self, span: Span, message: impl Into<String>) -> SyntaxError {
+        SyntaxError::new(self.source, span, message)
+    }
+
+    fn skip_whitespace(&mut self) -> Result<(), SyntaxError> {
+        while let Some(c) = self.peek() {
+            let rest = &self.source[self.pos..];
+            if c == ';' || rest.starts_with("#lang") || rest.starts_with("#!") {
+                while !matches!(self.next(), Some('\n') | None) {}
+            } else if rest.starts_with("#|") {
+                let start = self.pos;
+                let end = rest
+                    .find("|#")
+                    .ok_or_else(|| self.error(start..start + 2, "unterminated block comment"))?;
+                self.pos += end + 2;
+            } else if rest.starts_with("#;") {
+                self.pos += 2;
+                self.read()?;
+            } else if c.is_whitespace() {
+                self.next();
+            } else {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn token(&mut self) -> &str {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| !is_delimiter(c)) {
+            self.next();
+        }
+        &self.source[start..self.pos]
+    }
+
+    fn read(&mut self) -> Result<Sexp, SyntaxError> {
+        self.skip_whitespace()?;
+        let start = self.pos;
+        let c = self
+            .peek()
+            .ok_or_else(|| self.error(start..start, "unexpected end of input"))?;
+        let kind = match c {
+            '(' | '[' => {
+                self.next();
+                let close = if c == '(' { ')' } else { ']' };
+                let mut items = Vec::new();
+                loop {
+                    self.skip_whitespace()?;
+                    match self.peek() {
+                        Some(c) if c == close => {
+                            self.next();
+                            break SexpKind::List(items);
+                        }
+                        Some(c @ (')' | ']')) => {
+                            return Err(self.error(
+                                self.pos..self.pos + 1,
+                                format!("expected `{}` to close list, found `{}`", close, c),
+                            ));
+                        }
+                        Some(_) => items.push(self.read()?),
+                        None => return Err(self.error(start..self.pos, "unclosed list")),
+                    }
+                }
+            }
+            ')' | ']' => return Err(self.error(start..start + 1, "unexpected closing paren")),
+            '\'' => {
+                self.next();
+                let quoted = self.read()?;
+                let quote = Sexp {
+                    kind: SexpKind::Symbol("quote".to_owned()),
+                    span: start..start + 1,
+                };
+                SexpKind::List(vec![quote, quoted])
+            }
+            '"' => {
+                self.next();
+                let mut s = String::new();
+                loop {
+                    match self.next() {
+                        Some('"') => break SexpKind::String(s),
+                        Some('\\') => match self.next() {
+                            Some('a') => s.push('\u{7}'),
+                            Some('b') => s.push('\u{8}'),
+                            Some('t') => s.push('\t'),
+                            Some('n') => s.push('\n'),
+                            Some('v') => s.push('\u{b}'),
+                            Some('f') => s.push('\u{c}'),
+                            Some('r') => s.push('\r'),
+                            Some('e') => s.push('\u{1b}'),
+                            Some(c @ ('\\' | '"' | '\'')) => s.push(c),
+                            // Octal, as in `\0` or `\177`, and hexadecimal escapes
+                            Some(e @ ('0'..='7' | 'x' | 'u' | 'U')) => {
+                                let (radix, digits, first) = match e {
+                                    'x' => (16, 2, ""),
+                                    'u' => (16, 4, ""),
+                                    'U' => (16, 8, ""),
+                                    _ => (8, 2, &self.source[self.pos - 1..self.pos]),
+                                };
+                                let rest: String = self.source[self.pos..]
+                                    .chars()
+                                    .take(digits)
+                                    .take_while(|c| c.is_digit(radix))
+                                    .collect();
+                                self.pos += rest.len();
+                                let code = format!("{}{}", first, rest);
+                                match u32::from_str_radix(&code, radix)
+                                    .ok()
+                                    .and_then(char::from_u32)
+                                {
+                                    Some(c) => s.push(c),
+                                    None => {
+                                        let escape =
+                                            &self.source[self.pos - rest.len() - 2..self.pos];
+                                        return Err(self.error(
+                                            self.pos - escape.len()..self.pos,
+                                            format!("invalid escape `{}` in string", escape),
+                                        ));
+                                    }
+                                }
+                            }
+                            Some(c) => {
+                                return Err(self.error(
+                                    self.pos - c.len_utf8() - 1..self.pos,
+                                    format!("unknown escape `\\{}` in string", c),
+                                ));
+                            }
+                            None => return Err(self.error(start..self.pos, "unterminated string")),
+                        },
+                        Some(c) => s.push(c),
+                        None => return Err(self.error(start..self.pos, "unterminated string")),
+                    }
+                }
+            }
+            _ if self.source[self.pos..].starts_with("#\\") => {
+                self.pos += 2;
+                // The character itself may be a delimiter, as in #\(
+                let first = self
+                    .next()
+                    .ok_or_else(|| self.error(start..self.pos, "unterminated character"))?;
+                let name = format!("{}{}", first, self.token());
+                SexpKind::Character(self.character(&name, start)?)
+            }
+            _ => {
+                let token = self.token().to_owned();
+                match token.as_str() {
+                    "#t" | "#true" => SexpKind::Boolean(true),
+                    "#f" | "#false" => SexpKind::Boolean(false),
+                    _ if token.starts_with('#') => {
+                        return Err(
+                            self.error(start..self.pos, format!("unsupported syntax `{}`", token))
+                        );
+                    }
+                    _ => match token.parse() {
+                        Ok(i) => SexpKind::Integer(i),
+                        Err(_) if is_integer(&token) => {
+                            return Err(self.error(start..self.pos, "integer literal too large"));
+                        }
+                        Err(_) if is_number(&token) => {
+                            return Err(self.error(
+                                start..self.pos,
+                                format!("unsupported number `{}`: only integers are", token),
+                            ));
+                        }
+                        Err(_) => SexpKind::Symbol(token),
+                    },
+                }
+            }
+        };
+        Ok(Sexp {
+            kind,
+            span: start..self.pos,
+        })
+    }
+
+    fn character(&self, name: &str, start: usize) -> Result<char, SyntaxError> {
+        let mut chars = name.chars();
+        if let (Some(c), None) = (chars.next(), chars.next()) {
+            return Ok(c);
+        }
+        if let Some(&(_, c)) = CHAR_NAMES.iter().find(|&&(n, _)| n == name) {
+            return Ok(c);
+        }
+        name.strip_prefix(['u', 'U', 'x'])
+            .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+            .and_then(char::from_u32)
+            .ok_or_else(|| self.error(start..self.pos, format!("unknown character `#\\{}`", name)))
+    }
+}
+
+/// Reads every top-level s-expression in `source`, skipping `#lang` lines
+/// and comments
+pub fn read_all(source: &str) -> Result<Vec<Sexp>, SyntaxError> {
+    let mut reader = Reader { source, pos: 0 };
+    let mut sexps = Vec::new();
+    loop {
+        reader.skip_whitespace()?;
+        if reader.peek().is_none() {
+            return Ok(sexps);
+        }
+        sexps.push(reader.read()?);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_atoms_and_lists() {
+        let sexps =
+            read_all("#lang racket\n; comment\n(f -12 #t #\\a #\\space \"s\\\"\" 'x [y])").unwrap();
+        assert_eq!(sexps.len(), 1);
+        assert_eq!(
+            sexps[0].to_string(),
+            "(f -12 #t #\\a #\\space \"s\\\"\" (quote x) (y))"
+        );
+        assert_eq!(sexps[0].span, 23..58);
+        assert_eq!(read_all("#\\(").unwrap()[0].kind, SexpKind::Character('('));
+    }
+
+    #[test]
+    fn reports_error_locations() {
+        let e = read_all("#lang racket\n(if 1\n  2 3]").unwrap_err();
+        assert_eq!((e.line, e.column), (3, 6));
+        let e = read_all("(add1 1").unwrap_err();
+        assert_eq!(
+            (e.span.clone(), e.message.as_str()),
+            (0..7, "unclosed list")
+        );
+    }
+
+    #[test]
+    fn string_literals_read_back() {
+        let s = "a\"b\\c\nd\te\r\0\u{7}\u{7f}\u{85}é";
+        let literal = string_literal(s);
+        assert_eq!(
+            literal,
+            "\"a\\\"b\\\\c\\nd\\te\\r\\u0000\\u0007\\u007f\\u0085é\""
+        );
+        assert_eq!(
+            read_all(&literal).unwrap()[0].kind,
+            SexpKind::String(s.to_owned())
+        );
+        // A digit after an escape isn't taken as part of it
+        let s = "\u{1}1\u{1f}f";
+        assert_eq!(
+            read_all(&string_literal(s)).unwrap()[0].kind,
+            SexpKind::String(s.to_owned())
+        );
+    }
+
+    #[test]
+    fn reads_racket_escapes_and_numbers() {
+        let sexp = &read_all(r#""\a\b\v\f\e\'\0\101\08\x41\u3bb""#).unwrap()[0];
+        assert_eq!(
+            sexp.kind,
+            SexpKind::String("\u{7}\u{8}\u{b}\u{c}\u{1b}'\0A\08Aλ".to_owned())
+        );
+        let e = read_all(r#""\x""#).unwrap_err();
+        assert_eq!(
+            (e.span, e.message.as_str()),
+            (1..3, "invalid escape `\\x` in string")
+        );
+
+        for number in ["1.5", "-.5", "1e3", "1/2", "+inf.0"] {
+            let e = read_all(number).unwrap_err();
+            assert!(e.message.contains("only integers"), "{}: {}", number, e);
+        }
+        for symbol in ["1+", "inf", "-", "...", "1/x"] {
+            assert_eq!(read_all(symbol).unwrap()[0].symbol(), Some(symbol));
+        }
+        assert_eq!(read_all("-12").unwrap()[0].kind, SexpKind::Integer(-12));
+    }
+
+    #[test]
+    fn ascii_escapes_read_back() {
+        let source = "(cons #\\λ (cons #\\\" \"a\\\"é😀b\"))";
+        let ascii = to_ascii(source);
+        assert_eq!(
+            ascii,
+            "(cons #\\u03bb (cons #\\\" \"a\\\"\\u00e9\\U0001f600b\"))"
+        );
+        assert_eq!(read_all(&ascii).unwrap(), read_all(source).unwrap());
+    }
+}
@@ -0,0 +1,32 @@
+# test-programs/read-byte.s as a macOS build emits it, before linking.
+# Assembled into read-byte-macos.o with
+# `llvm-mc -triple=x86_64-apple-macosx10.15 -filetype=obj -o read-byte-macos.o read-byte-macos.s`.
+        .intel_syntax noprefix
+        .text
+        .globl _entry
+        .extern _read_byte
+        .extern _raise_error
+_entry:
+        push rbx
+        push r15
+        mov rbx, rdi
+        add rbx, 0
+        mov r15, rsp
+        and r15, 8
+        sub rsp, r15
+        call _read_byte
+        add rsp, r15
+        mov r9, rax
+        and r9, 15
+        cmp r9, 0
+        jne err
+        add rax, 16
+        add rsp, 0
+        pop r15
+        pop rbx
+        ret
+err:
+        mov r15, rsp
+        and r15, 8
+        sub rsp, r15
+        call _raise_error
@@ -0,0 +1,31 @@
+# `(add1 (read-byte))` as the course compiler emits it, before linking.
+# Assembled into read-byte.o with `as --64 -o read-byte.o read-byte.s`.
+        .intel_syntax noprefix
+        .text
+        .globl entry
+        .extern read_byte
+        .extern raise_error
+entry:
+        push rbx
+        push r15
+        mov rbx, rdi
+        add rbx, 0
+        mov r15, rsp
+        and r15, 8
+        sub rsp, r15
+        call read_byte
+        add rsp, r15
+        mov r9, rax
+        and r9, 15
+        cmp r9, 0
+        jne err
+        add rax, 16
+        add rsp, 0
+        pop r15
+        pop rbx
+        ret
+err:
+        mov r15, rsp
+        and r15, 8
+        sub rsp, r15
+        call raise_error
@@ -0,0 +1,19 @@
+# Stores of immediates to a global through RIP-relative operands, whose
+# relocations are X86_64_RELOC_SIGNED_1, _2 and _4 as the immediate follows
+# the displacement. Assembled into rip-relative-macos.o with
+# `llvm-mc -triple=x86_64-apple-macosx10.15 -filetype=obj -o rip-relative-macos.o rip-relative-macos.s`.
+        .intel_syntax noprefix
+        .text
+        .globl _entry
+_entry:
+        mov byte ptr [rip + _data], 1
+        mov word ptr [rip + _data], 2
+        mov dword ptr [rip + _data], 3
+        mov qword ptr [rip + _data + 8], 4
+        mov rax, qword ptr [rip + _data]
+        ret
+        .data
+        .globl _data
+_data:
+        .quad 0
+        .quad 0
@@ -0,0 +1,28 @@
+use std::{fs, process::Command};
+
+#[test]
+fn unsupported_programs_exit_with_status_1() {
+    let path = std::env::temp_dir().join(format!(
+        "compiler-deconstruction-{}-untagged.s",
+        std::process::id()
+    ));
+    // A value with no type's tag
+    fs::write(
+        &path,
+        "global entry\nentry:\n push rbx\n push r15\n mov rbx, rdi\n add rbx, 0\n \
+         mov rax, 7\n add rsp, 0\n pop r15\n pop rbx\n ret\n",
+    )
+    .unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_compiler-deconstruction"))
+        .arg("decompile")
+        .arg(&path)
+        .output()
+        .unwrap();
+    fs::remove_file(&path).unwrap();
+
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.starts_with("error at 0x"), "{}", stderr);
+    assert!(stderr.ends_with(": unknown constant\n"), "{}", stderr);
+    assert!(!stderr.contains("panicked"), "{}", stderr);
+}